@@ -1,2 +0,0 @@
-pub mod dht ;
-pub mod wire ;
\ No newline at end of file
@@ -1,57 +1,552 @@
 extern crate bencode;
+extern crate clap;
 extern crate rand;
+extern crate sha1;
+extern crate tokio;
+extern crate tokio_stream;
 
-use rand::prelude::*;
-use self::bencode::{Bencode, FromBencode, ToBencode};
+use self::bencode::Bencode;
 use self::bencode::util::ByteString;
-use std::io::Write;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use spider::blocklist::Blocklist;
+use spider::catalog::{Catalog, Search};
+use spider::config::Settings;
+use spider::dedupe::Claim;
+use spider::dht::{Announce, Status};
+use spider::fetcher::Pool;
+use spider::api::{Api, PerMinute, Stats};
+use spider::id::InfoHash;
+use spider::metrics::Metrics;
+use spider::rules::Filter;
+use spider::store::TorrentStore;
+use spider::wire::{Torrent, WireError};
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+use tracing::{debug, error, info, info_span, warn};
 
-mod lib;
+mod spider;
+
+const STATS_INTERVAL_SEC: u64 = 60;
+/// tries of a fetch failing with a retryable error, see `WireError::is_retryable`
+const FETCH_ATTEMPTS: u32 = 2;
+const FETCH_RETRY_DELAY_SEC: u64 = 1;
 
 fn main() {
-    println!("hello world");
+    let output = Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .takes_value(true)
+        .help("directory or database the fetched torrents are kept in [default: .]");
+    let matches = App::new("p2pspider")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("DHT spider collecting the metadata of announced torrents")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .takes_value(true)
+            .help("TOML or JSON settings file, see config.example.toml"))
+        .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .takes_value(true)
+            .possible_values(&spider::logging::FORMATS)
+            .help("how log events are written to stderr [default: text]"))
+        .arg(Arg::with_name("log-filter")
+            .long("log-filter")
+            .takes_value(true)
+            .help("which log events are written, e.g. info,p2pspider::spider::dht=debug [default: info]"))
+        .subcommand(SubCommand::with_name("crawl")
+            .about("join the DHT and fetch the metadata of every announced torrent")
+            .arg(Arg::with_name("bind")
+                .short("b")
+                .long("bind")
+                .takes_value(true)
+                .help("UDP address the DHT node listens on [default: 0.0.0.0:34254]"))
+            .arg(Arg::with_name("bind6")
+                .long("bind6")
+                .takes_value(true)
+                .help("UDP address of the IPv6 DHT node, empty to disable [default: [::]:34254]"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("TCP address serving the fetched metadata to other peers"))
+            .arg(Arg::with_name("api")
+                .long("api")
+                .takes_value(true)
+                .help("HTTP address of the JSON API, e.g. 127.0.0.1:8080"))
+            .arg(Arg::with_name("metrics")
+                .long("metrics")
+                .takes_value(true)
+                .help("HTTP address of the Prometheus metrics, e.g. 127.0.0.1:9100"))
+            .arg(Arg::with_name("id")
+                .long("id")
+                .takes_value(true)
+                .help("node ID as 40 hex characters, random if omitted"))
+            .arg(Arg::with_name("max-friends-per-sec")
+                .short("f")
+                .long("max-friends-per-sec")
+                .takes_value(true)
+                .help("maximum find_node queries sent per second [default: 50]"))
+            .arg(Arg::with_name("max-bytes-per-sec")
+                .long("max-bytes-per-sec")
+                .takes_value(true)
+                .help("cap of the DHT traffic sent per node, 0 for none [default: 0]"))
+            .arg(Arg::with_name("secret")
+                .long("secret")
+                .takes_value(true)
+                .help("secret used to generate announce tokens"))
+            .arg(Arg::with_name("bootstrap")
+                .long("bootstrap")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("host:port of a bootstrap node, may be repeated"))
+            .arg(Arg::with_name("blocklist")
+                .long("blocklist")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("file of IP ranges to ignore, CIDR, P2P or eMule ipfilter.dat, may be repeated"))
+            .arg(output.clone()))
+        .subcommand(SubCommand::with_name("fetch")
+            .about("fetch the metadata of one torrent from one peer")
+            .arg(Arg::with_name("infohash")
+                .required(true)
+                .help("info hash as 40 hex or 32 base32 characters"))
+            .arg(Arg::with_name("peer")
+                .required(true)
+                .help("host:port of a peer having the torrent"))
+            .arg(output.clone()))
+        .subcommand(SubCommand::with_name("list")
+            .about("print the info hash of every stored torrent")
+            .arg(output.clone()))
+        .subcommand(SubCommand::with_name("show")
+            .about("print what the catalogue knows about a torrent")
+            .arg(Arg::with_name("infohash")
+                .required(true)
+                .help("info hash as 40 hex or 32 base32 characters")))
+        .subcommand(SubCommand::with_name("search")
+            .about("search the catalogue by torrent name and file path")
+            .arg(Arg::with_name("query")
+                .required(true)
+                .multiple(true)
+                .help("terms looked up in names and file paths, all of them must match"))
+            .arg(Arg::with_name("min-size")
+                .long("min-size")
+                .takes_value(true)
+                .help("smallest total size, e.g. 700M"))
+            .arg(Arg::with_name("max-size")
+                .long("max-size")
+                .takes_value(true)
+                .help("largest total size, e.g. 4G"))
+            .arg(Arg::with_name("min-files")
+                .long("min-files")
+                .takes_value(true)
+                .help("fewest files"))
+            .arg(Arg::with_name("max-files")
+                .long("max-files")
+                .takes_value(true)
+                .help("most files"))
+            .arg(Arg::with_name("limit")
+                .short("n")
+                .long("limit")
+                .takes_value(true)
+                .help("number of results [default: 20]")))
+        .subcommand(SubCommand::with_name("inspect")
+            .about("print the content of a .torrent file")
+            .arg(Arg::with_name("file")
+                .required(true)
+                .help("path of the .torrent file")))
+        .get_matches();
+
+    let settings = match matches.value_of("config") {
+        Some(path) => spider::config::load(path),
+        None => spider::config::from_env(),
+    };
+    let result = settings.and_then(|mut settings| {
+        if let Some(format) = matches.value_of("log-format") {
+            settings.log.format = format.to_string();
+        }
+        if let Some(filter) = matches.value_of("log-filter") {
+            settings.log.filter = filter.to_string();
+        }
+        settings.init_logging()?;
+        match matches.subcommand() {
+            ("crawl", Some(m)) => crawl(m, settings),
+            ("fetch", Some(m)) => fetch(m, settings),
+            ("list", Some(m)) => list(m, settings),
+            ("show", Some(m)) => show(m, settings),
+            ("search", Some(m)) => search(m, settings),
+            ("inspect", Some(m)) => inspect(m),
+            _ => Ok(()),
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
 
-fn run() {
-    let mut d = lib::dht::new_dht();
-    d = d
-        .local_id("0.0.0.0".as_bytes().to_vec())
-        .max_friends_per_sec(50)
-        .secret("tmp-secret".to_string());
-    let (handles, rx) = d.start();
+fn crawl(m: &ArgMatches, mut settings: Settings) -> Result<(), String> {
+    if let Some(bind) = m.value_of("bind") {
+        settings.dht.bind = bind.to_string();
+    }
+    if let Some(bind) = m.value_of("bind6") {
+        settings.dht.bind6 = bind.to_string();
+    }
+    if let Some(seed) = m.value_of("seed") {
+        settings.seeder.bind = seed.to_string();
+    }
+    if let Some(api) = m.value_of("api") {
+        settings.api.bind = api.to_string();
+    }
+    if let Some(metrics) = m.value_of("metrics") {
+        settings.metrics.bind = metrics.to_string();
+    }
+    if let Some(id) = m.value_of("id") {
+        settings.dht.id = id.to_string();
+    }
+    if let Some(n) = m.value_of("max-friends-per-sec") {
+        settings.dht.max_friends_per_sec = n.parse().map_err(|_| format!("invalid max-friends-per-sec: {}", n))?;
+    }
+    if let Some(n) = m.value_of("max-bytes-per-sec") {
+        settings.dht.max_bytes_per_sec = n.parse().map_err(|_| format!("invalid max-bytes-per-sec: {}", n))?;
+    }
+    if let Some(secret) = m.value_of("secret") {
+        settings.dht.secret = secret.to_string();
+    }
+    if let Some(nodes) = m.values_of("bootstrap") {
+        settings.dht.bootstraps = nodes.map(|s| s.to_string()).collect();
+    }
+    if let Some(files) = m.values_of("blocklist") {
+        settings.blocklist.files = files.map(|s| s.to_string()).collect();
+    }
+    if let Some(out) = m.value_of("output") {
+        settings.output = out.to_string();
+    }
+    settings.validate()?;
+    let metrics = Arc::new(Metrics::new());
+    let blocklist = Arc::new(settings.blocklist()?);
+    let d = settings.dht().map_err(|e| format!("couldn't bind {}: {}", settings.dht.bind, e))?
+        .metrics(metrics.clone())
+        .blocklist(blocklist.clone());
+    let d6 = match settings.dht6() {
+        Some(Ok(d6)) => Some(d6.metrics(metrics.clone()).blocklist(blocklist.clone())),
+        Some(Err(e)) => {
+            // hosts without IPv6 still crawl on IPv4
+            warn!(addr = %settings.dht.bind6, error = %e, "couldn't bind the IPv6 node, running on IPv4 only");
+            None
+        }
+        None => None,
+    };
+    let store = settings.store()?;
+    if let Some(seeder) = settings.seeder() {
+        let seeder = seeder.map_err(|e| format!("couldn't bind {}: {}", settings.seeder.bind, e))?;
+        if let Ok(addr) = seeder.local_addr() {
+            info!(%addr, "serving metadata");
+        }
+        let store = store.clone();
+        seeder.start(move |hash| store.get(hash).unwrap_or_default());
+    }
+    if let Some(exporter) = settings.metrics(metrics.clone()) {
+        let exporter = exporter.map_err(|e| format!("couldn't bind {}: {}", settings.metrics.bind, e))?;
+        if let Some(addr) = exporter.local_addr() {
+            info!("serving metrics on http://{}/metrics", addr);
+        }
+        exporter.start();
+    }
+    let catalog = settings.catalog().transpose()?.map(Arc::new);
+    let mut api = settings.api(store.clone()).transpose()
+        .map_err(|e| format!("couldn't bind {}: {}", settings.api.bind, e))?;
+    if let Some(ref c) = catalog {
+        api = api.map(|a| a.catalog(c.clone()));
+    }
+    let rt = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    rt.block_on(run(d, d6, &settings, store, catalog, api, metrics, blocklist))
+}
+
+fn fetch(m: &ArgMatches, mut settings: Settings) -> Result<(), String> {
+    let hash: InfoHash = m.value_of("infohash").unwrap_or_default().parse()?;
+    let peer = m.value_of("peer").unwrap_or_default();
+    if let Some(out) = m.value_of("output") {
+        settings.output = out.to_string();
+    }
+    settings.validate()?;
+    let store = settings.store()?;
+    let t = fetch_and_save(hash, peer.to_string(), &settings, &*store)?;
+    if let Some(catalog) = settings.catalog() {
+        catalog?.add(&t)?;
+    }
+    print!("{}", t);
+    Ok(())
+}
+
+fn list(m: &ArgMatches, mut settings: Settings) -> Result<(), String> {
+    if let Some(out) = m.value_of("output") {
+        settings.output = out.to_string();
+    }
+    settings.validate()?;
+    for hash in settings.store()?.list()? {
+        println!("{}", hash);
+    }
+    Ok(())
+}
+
+fn show(m: &ArgMatches, settings: Settings) -> Result<(), String> {
+    let hash: InfoHash = m.value_of("infohash").unwrap_or_default().parse()?;
+    let catalog = settings.catalog().ok_or("the catalogue is disabled")??;
+    let r = catalog.get(&hash)?.ok_or_else(|| format!("{} is not in the catalogue", hash))?;
+    print!("{}", r.torrent);
+    println!("added: {}\nfirst seen: {}\nlast seen: {}\nannounces: {}", r.added, r.first_seen, r.last_seen, r.announces);
+    for f in r.torrent.files.iter() {
+        println!("  {} {}", f.length, f.name);
+    }
+    Ok(())
+}
+
+fn search(m: &ArgMatches, settings: Settings) -> Result<(), String> {
+    let mut q = Search {
+        text: m.values_of("query").map(|v| v.collect::<Vec<_>>().join(" ")).unwrap_or_default(),
+        ..Default::default()
+    };
+    q.min_size = m.value_of("min-size").map(parse_size).transpose()?;
+    q.max_size = m.value_of("max-size").map(parse_size).transpose()?;
+    q.min_files = m.value_of("min-files").map(|n| n.parse().map_err(|_| format!("invalid min-files: {}", n))).transpose()?;
+    q.max_files = m.value_of("max-files").map(|n| n.parse().map_err(|_| format!("invalid max-files: {}", n))).transpose()?;
+    if let Some(n) = m.value_of("limit") {
+        q.limit = n.parse().map_err(|_| format!("invalid limit: {}", n))?;
+    }
+    let catalog = settings.catalog().ok_or("the catalogue is disabled")??;
+    for h in catalog.search(&q)? {
+        println!("{} {:>12} {:>5} {}", h.hash, h.length, h.num_files, h.name);
+    }
+    Ok(())
+}
+
+/// Parses a size in bytes with an optional K, M, G or T suffix (powers of 1024).
+fn parse_size(s: &str) -> Result<i64, String> {
+    let err = || format!("invalid size: {}", s);
+    let (num, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let n: i64 = num.parse().map_err(|_| err())?;
+    n.checked_mul(1 << shift).ok_or_else(err)
+}
+
+fn inspect(m: &ArgMatches) -> Result<(), String> {
+    let name = m.value_of("file").unwrap_or_default();
+    let mut dat = vec![];
+    std::fs::File::open(name)
+        .and_then(|mut f| f.read_to_end(&mut dat))
+        .map_err(|e| format!("{}: {}", name, e))?;
+    let ben = bencode::from_vec(dat).map_err(|e| format!("{}: {}", name, e.msg))?;
+    let info = match ben {
+        Bencode::Dict(ref m) => m.get(&ByteString::from_str("info")).unwrap_or(&ben),
+        _ => return Err(format!("{}: not a torrent file", name)),
+    };
+    let info = info.to_bytes().map_err(|e| e.to_string())?;
+    let hash = InfoHash::from(sha1::Sha1::from(&info).digest().bytes());
+    let t = spider::wire::parse_data(info, hash).map_err(|_| format!("{}: invalid info dictionary", name))?;
+    print!("{}", t);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run(d: spider::dht::RustDHT, d6: Option<spider::dht::RustDHT>, settings: &Settings,
+             store: Arc<dyn TorrentStore>, catalog: Option<Arc<Catalog>>, api: Option<Api>,
+             metrics: Arc<Metrics>, blocklist: Arc<Blocklist>) -> Result<(), String> {
+    let dedupe = Arc::new(settings.dedupe(store.clone())?);
+    let rules = settings.rules().transpose()?.map(Arc::new);
+    let mut status = vec![d.status()];
+    status.extend(d6.as_ref().map(|d6| d6.status()));
+    let (_handles, mut announces) = match d6 {
+        Some(d6) => d.start_dual(d6),
+        None => d.start(),
+    }.map_err(|e| e.to_string())?;
+    let (s, st, c, m, dd) = (settings.clone(), store.clone(), catalog.clone(), metrics.clone(), dedupe.clone());
+    let (bl, r) = (blocklist.clone(), rules.clone());
+    let pool = Arc::new(Pool::start(settings.wire.workers, settings.wire.queue_len, move |announce: Announce| {
+        let result = fetch_announced(&announce, &s, &*st, c.as_deref(), &m, &bl, r.as_deref());
+        dd.release(&announce.info_hash, result.is_ok());
+        result
+    }));
+    let total = Arc::new(AtomicU64::new(0));
+    let per_minute = Arc::new(PerMinute::default());
+    if let Some(api) = api {
+        if let Some(addr) = api.local_addr() {
+            info!("serving the API on http://{}", addr);
+        }
+        let (p, total, per_minute) = (pool.clone(), total.clone(), per_minute.clone());
+        api.stats(move || Stats {
+            nodes: status.iter().map(|s| s.nodes()).sum(),
+            announces: total.load(Ordering::Relaxed),
+            announces_per_minute: per_minute.get(),
+            rtt_millis: mean_rtt_millis(&status),
+            fetch: p.total(),
+            fetch_dropped: p.dropped(),
+        }).start();
+    }
+    let (p, dd, bl) = (pool.clone(), dedupe.clone(), blocklist.clone());
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(STATS_INTERVAL_SEC));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            print_stats(&p);
+            bl.prune();
+            let dd = dd.clone();
+            if let Ok(Err(e)) = tokio::task::spawn_blocking(move || dd.save()).await {
+                error!(error = %e, "couldn't save the seen filter");
+            }
+        }
+    });
+    if let Some(ref rules) = rules {
+        let rules = rules.clone();
+        let mut interval = tokio::time::interval(Duration::from_secs(settings.rules.reload_sec));
+        tokio::spawn(async move {
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let rules = rules.clone();
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || rules.reload()).await {
+                    error!(error = %e, "couldn't reload the rules");
+                }
+            }
+        });
+    }
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
     loop {
-        match rx.recv() {
-            Ok(announce) => {
-                // todo
-                // if is_exist(announce.info_hash_hex){continue}
-                // if in_block_list(announce.info_hash_hex){continue}
-                let mut peer = lib::wire::new(announce.info_hash_hex.clone(), announce.peer.to_string()).unwrap();
-                let data = peer.fetch().unwrap_or_else(|e| {
-                    // todo add announce.peer to block list
-                    vec![]
-                });
-                let mut dict = bencode::DictMap::new();
-                if let Ok(ben) = bencode::from_vec(data) {
-                    dict.insert(ByteString::from_str("info"), ben);
-                    let bytes = Bencode::Dict(dict).to_bytes().unwrap_or(vec![]);
-                    let _ = save(announce.info_hash_hex.clone(), bytes.to_vec()).or_else(|e| {
-                        println!("{}", e.to_string());
-                        Err(e)
-                    });
-                    if let Ok(t) = lib::wire::parse_data(bytes.to_vec(), announce.info_hash_hex.clone()) {
-                        print!("{}", t)
-                    }
+        let announce = tokio::select! {
+            a = announces.next() => match a {
+                Some(a) => a,
+                None => break,
+            },
+            _ = &mut ctrl_c => {
+                info!("interrupted, saving the seen filter");
+                break;
+            }
+        };
+        total.fetch_add(1, Ordering::Relaxed);
+        per_minute.hit();
+        // banned after the node queued the announce
+        if blocklist.is_blocked(announce.peer.ip()) {
+            metrics.blocked.with_label_values(&["announce"]).inc();
+            continue;
+        }
+        // repeat announces still count towards popularity
+        if let Some(ref c) = catalog {
+            if let Err(e) = c.announced(&announce.info_hash) {
+                error!(info_hash = %announce.info_hash, error = %e, "couldn't record the announce");
+            }
+        }
+        if rules.as_ref().is_some_and(|r| r.drops_hash(&announce.info_hash)) {
+            metrics.filtered.with_label_values(&["announce"]).inc();
+            continue;
+        }
+        let claim = dedupe.claim(&announce.info_hash);
+        metrics.dedupe.with_label_values(&[claim.as_str()]).inc();
+        if claim != Claim::New {
+            continue;
+        }
+        let hash = announce.info_hash;
+        if !pool.submit(announce) {
+            dedupe.release(&hash, false);
+        }
+    }
+    dedupe.save()
+}
+
+/// Fetches, saves and catalogues the torrent of `announce` unless the rules
+/// drop it, penalizing its peer if it misbehaves.
+fn fetch_announced(announce: &Announce, settings: &Settings, store: &dyn TorrentStore, catalog: Option<&Catalog>,
+                   metrics: &Metrics, blocklist: &Blocklist, rules: Option<&Filter>) -> Result<(), String> {
+    let span = info_span!("fetch", peer = %announce.peer, info_hash = %announce.info_hash);
+    let _enter = span.enter();
+    let started = Instant::now();
+    let result = fetch_torrent(announce.info_hash, announce.peer.to_string(), settings);
+    let outcome = match result {
+        Ok(_) => "ok",
+        Err(ref e) => e.kind(),
+    };
+    metrics.fetched(outcome, started.elapsed());
+    match result {
+        Ok((t, data)) => {
+            info!(name = %t.name, length = t.length, millis = started.elapsed().as_millis() as u64, "fetched");
+            if let Some(rule) = rules.and_then(|r| r.drops(&t)) {
+                info!(rule = rule + 1, "dropped by the rules");
+                metrics.filtered.with_label_values(&["torrent"]).inc();
+                return Err(format!("dropped by rule #{}", rule + 1));
+            }
+            if let Err(e) = store.put(&announce.info_hash, &data) {
+                error!(error = %e, "couldn't save the torrent");
+                return Err(e);
+            }
+            if let Some(c) = catalog {
+                if let Err(e) = c.add(&t) {
+                    error!(error = %e, "couldn't add the torrent to the catalogue");
                 }
             }
-            Err(_) => continue,
+            Ok(())
+        }
+        Err(e) => {
+            debug!(error = %e, kind = outcome, "fetch failed");
+            if e.is_misbehaving() && blocklist.penalize(announce.peer.ip()) {
+                info!(banned = blocklist.banned(), "banned the peer");
+                metrics.bans.inc();
+            }
+            Err(e.to_string())
         }
     }
 }
 
-fn save(name: String, dat: Vec<u8>) -> Result<(), std::io::Error> {
-    if dat.len() == 0 { return Ok(()); }
+/// Mean of the round-trip times of the IPv4 and IPv6 nodes.
+fn mean_rtt_millis(status: &[Status]) -> Option<u64> {
+    let rtts: Vec<Duration> = status.iter().filter_map(|s| s.mean_rtt()).collect();
+    if rtts.is_empty() {
+        return None;
+    }
+    Some((rtts.iter().sum::<Duration>() / rtts.len() as u32).as_millis() as u64)
+}
 
-    let mut f = std::fs::File::create(format!("{}.torrent", name))?;
-    f.write_all(dat.as_ref())?;
-    Ok(())
+fn print_stats(pool: &Pool<Announce>) {
+    info!(dropped = pool.dropped(), "fetch: {}", pool.total());
+    for (i, s) in pool.stats().iter().enumerate() {
+        debug!(worker = i, "fetch: {}", s);
+    }
+}
+
+/// Downloads the info dictionary of `hash` from `peer`, returning it parsed
+/// and raw. Failures that may not happen again are retried.
+fn fetch_torrent(hash: InfoHash, peer: String, settings: &Settings) -> Result<(Torrent, Vec<u8>), WireError> {
+    let mut attempt = 1;
+    loop {
+        match fetch_once(hash, peer.clone(), settings) {
+            Err(e) if e.is_retryable() && attempt < FETCH_ATTEMPTS => {
+                debug!(error = %e, attempt, "retrying the fetch");
+                std::thread::sleep(Duration::from_secs(FETCH_RETRY_DELAY_SEC));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn fetch_once(hash: InfoHash, peer: String, settings: &Settings) -> Result<(Torrent, Vec<u8>), WireError> {
+    let data = settings.wire(hash, peer)?.fetch()?;
+    let t = spider::wire::parse_data(data.clone(), hash)?;
+    Ok((t, data))
+}
+
+fn fetch_and_save(hash: InfoHash, peer: String, settings: &Settings, store: &dyn TorrentStore) -> Result<Torrent, String> {
+    let (t, data) = fetch_torrent(hash, peer, settings).map_err(|e| e.to_string())?;
+    store.put(&hash, &data)?;
+    Ok(t)
 }
@@ -0,0 +1,351 @@
+//! Local HTTP API answering JSON:
+//!
+//! - `GET /torrents/{infohash}` the parsed info dictionary from the store,
+//!   with what the catalogue knows about it
+//! - `GET /search?q=…` full-text search, also takes `min_size`, `max_size`,
+//!   `min_files`, `max_files` and `limit`
+//! - `GET /recent?limit=…` the torrents fetched last
+//! - `GET /stats` crawler status, see `Stats`
+//!
+//! Requests are served one at a time on a background thread.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use super::catalog::{Catalog, Record, Search};
+use super::fetcher::WorkerStats;
+use super::id::InfoHash;
+use super::store::TorrentStore;
+use super::wire::{self, Torrent};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 1000;
+
+/// Crawler status reported by `/stats`.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// nodes in the routing tables
+    pub nodes: usize,
+    pub announces: u64,
+    pub announces_per_minute: u64,
+    /// mean round-trip time of the nodes, `None` before any answered
+    pub rtt_millis: Option<u64>,
+    /// all fetch workers together
+    pub fetch: WorkerStats,
+    /// announces dropped because every fetch worker was busy
+    pub fetch_dropped: u64,
+}
+
+/// Counts events over the last minute.
+#[derive(Default)]
+pub struct PerMinute {
+    /// (unix second, events in it), oldest first
+    seconds: Mutex<VecDeque<(u64, u64)>>,
+}
+
+impl PerMinute {
+    pub fn hit(&self) {
+        let now = unix_now();
+        let mut s = self.seconds.lock().unwrap();
+        match s.back_mut() {
+            Some(last) if last.0 == now => last.1 += 1,
+            _ => s.push_back((now, 1)),
+        }
+        expire(&mut s, now);
+    }
+
+    pub fn get(&self) -> u64 {
+        let mut s = self.seconds.lock().unwrap();
+        expire(&mut s, unix_now());
+        s.iter().map(|c| c.1).sum()
+    }
+}
+
+fn expire(s: &mut VecDeque<(u64, u64)>, now: u64) {
+    while s.front().is_some_and(|c| c.0 + 60 <= now) {
+        s.pop_front();
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+type StatsFn = dyn Fn() -> Stats + Send + Sync;
+
+pub struct Api {
+    server: tiny_http::Server,
+    store: Arc<dyn TorrentStore>,
+    catalog: Option<Arc<Catalog>>,
+    stats: Arc<StatsFn>,
+}
+
+/// Binds the API, serving the torrents of `store`.
+pub fn bind(addr: &str, store: Arc<dyn TorrentStore>) -> Result<Api, io::Error> {
+    let server = tiny_http::Server::http(addr).map_err(io::Error::other)?;
+    Ok(Api { server, store, catalog: None, stats: Arc::new(Stats::default) })
+}
+
+impl Api {
+    /// Enables `/search` and `/recent` and adds the announce times to
+    /// `/torrents`.
+    pub fn catalog(mut self, c: Arc<Catalog>) -> Api {
+        self.catalog = Some(c);
+        self
+    }
+
+    /// Sets what `/stats` reports.
+    pub fn stats<F: Fn() -> Stats + Send + Sync + 'static>(mut self, f: F) -> Api {
+        self.stats = Arc::new(f);
+        self
+    }
+
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    pub fn start(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for req in self.server.incoming_requests() {
+                let (status, body) = self.handle(req.method(), req.url());
+                let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                    .expect("static header");
+                let resp = tiny_http::Response::from_string(body.to_string())
+                    .with_status_code(status)
+                    .with_header(header);
+                let _ = req.respond(resp);
+            }
+        })
+    }
+
+    fn handle(&self, method: &tiny_http::Method, url: &str) -> (u16, Value) {
+        if *method != tiny_http::Method::Get {
+            return error(405, "only GET is supported");
+        }
+        let (path, query) = match url.find('?') {
+            Some(i) => (&url[..i], parse_query(&url[i + 1..])),
+            None => (url, HashMap::new()),
+        };
+        let result = match path.trim_end_matches('/') {
+            "/search" => self.search(&query),
+            "/recent" => self.recent(&query),
+            "/stats" => Ok(self.stats_json()),
+            p if p.starts_with("/torrents/") => self.torrent(&p["/torrents/".len()..]),
+            _ => Err(error(404, "not found")),
+        };
+        match result {
+            Ok(v) => (200, v),
+            Err(e) => e,
+        }
+    }
+
+    fn torrent(&self, hash: &str) -> Result<Value, (u16, Value)> {
+        let hash: InfoHash = hash.parse().map_err(|e: String| error(400, &e))?;
+        let info = self.store.get(&hash).map_err(|e| error(500, &e))?.ok_or_else(|| error(404, "unknown info hash"))?;
+        let t = wire::parse_data(info, hash).map_err(|_| error(500, "invalid info dictionary in the store"))?;
+        let mut v = torrent_json(&t);
+        if let Some(ref c) = self.catalog {
+            if let Some(r) = c.get(&hash).map_err(|e| error(500, &e))? {
+                v["added"] = json!(r.added);
+                v["first_seen"] = json!(r.first_seen);
+                v["last_seen"] = json!(r.last_seen);
+                v["announces"] = json!(r.announces);
+            }
+        }
+        Ok(v)
+    }
+
+    fn search(&self, query: &HashMap<String, String>) -> Result<Value, (u16, Value)> {
+        let c = self.catalog.as_ref().ok_or_else(|| error(503, "the catalogue is disabled"))?;
+        let q = Search {
+            text: query.get("q").cloned().unwrap_or_default(),
+            min_size: param(query, "min_size")?,
+            max_size: param(query, "max_size")?,
+            min_files: param(query, "min_files")?,
+            max_files: param(query, "max_files")?,
+            limit: limit(query)?,
+        };
+        let hits = c.search(&q).map_err(|e| error(400, &e))?;
+        Ok(Value::Array(hits.into_iter().map(|h| json!({
+            "info_hash": h.hash.to_hex(),
+            "name": h.name,
+            "length": h.length,
+            "num_files": h.num_files,
+            "rank": h.rank,
+        })).collect()))
+    }
+
+    fn recent(&self, query: &HashMap<String, String>) -> Result<Value, (u16, Value)> {
+        let c = self.catalog.as_ref().ok_or_else(|| error(503, "the catalogue is disabled"))?;
+        let records = c.recent(limit(query)?).map_err(|e| error(500, &e))?;
+        Ok(Value::Array(records.iter().map(record_json).collect()))
+    }
+
+    fn stats_json(&self) -> Value {
+        let s = (self.stats)();
+        let mut v = json!({
+            "dht": {
+                "nodes": s.nodes,
+                "announces": s.announces,
+                "announces_per_minute": s.announces_per_minute,
+                "rtt_millis": s.rtt_millis,
+            },
+            "fetch": {
+                "fetched": s.fetch.fetched,
+                "failed": s.fetch.failed,
+                "dropped": s.fetch_dropped,
+                "success_rate": s.fetch.success_rate(),
+                "mean_millis": s.fetch.mean_millis(),
+                "max_millis": s.fetch.max_millis,
+            },
+        });
+        if let Some(n) = self.catalog.as_ref().and_then(|c| c.count().ok()) {
+            v["catalogue"] = json!({ "torrents": n });
+        }
+        v
+    }
+}
+
+fn torrent_json(t: &Torrent) -> Value {
+    json!({
+        "info_hash": t.hash.to_hex(),
+        "magnet": format!("magnet:?xt=urn:btih:{}", t.hash),
+        "name": t.name,
+        "length": t.length,
+        "files": t.files.iter().map(|f| json!({ "path": f.name, "length": f.length })).collect::<Vec<_>>(),
+    })
+}
+
+fn record_json(r: &Record) -> Value {
+    let mut v = torrent_json(&r.torrent);
+    v["added"] = json!(r.added);
+    v["first_seen"] = json!(r.first_seen);
+    v["last_seen"] = json!(r.last_seen);
+    v["announces"] = json!(r.announces);
+    v
+}
+
+fn error(status: u16, msg: &str) -> (u16, Value) {
+    (status, json!({ "error": msg }))
+}
+
+fn param(query: &HashMap<String, String>, key: &str) -> Result<Option<i64>, (u16, Value)> {
+    match query.get(key) {
+        Some(v) => v.parse().map(Some).map_err(|_| error(400, &format!("{}: not a number", key))),
+        None => Ok(None),
+    }
+}
+
+fn limit(query: &HashMap<String, String>) -> Result<usize, (u16, Value)> {
+    let n = param(query, "limit")?.unwrap_or(DEFAULT_LIMIT as i64);
+    if n < 1 || n as usize > MAX_LIMIT {
+        return Err(error(400, &format!("limit: out of range 1..{}", MAX_LIMIT)));
+    }
+    Ok(n as usize)
+}
+
+/// Decodes `a=1&b=x+y%21` style query strings, the last value of a key wins.
+fn parse_query(q: &str) -> HashMap<String, String> {
+    q.split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.find('=') {
+            Some(i) => (percent_decode(&p[..i]), percent_decode(&p[i + 1..])),
+            None => (percent_decode(p), String::new()),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < b.len() => match (hex_value(b[i + 1]), hex_value(b[i + 2])) {
+                (Some(h), Some(l)) => {
+                    out.push(h << 4 | l);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::catalog;
+    use super::super::store::fs::FsStore;
+    use super::super::testutil::TempDir;
+    use std::io::{Read, Write};
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> (u16, Value) {
+        let mut conn = std::net::TcpStream::connect(addr).unwrap();
+        write!(conn, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut resp = String::new();
+        conn.read_to_string(&mut resp).unwrap();
+        let status = resp[9..12].parse().unwrap();
+        let body = &resp[resp.find("\r\n\r\n").unwrap() + 4..];
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn endpoints() {
+        let dir = TempDir::new("api");
+        let store = Arc::new(FsStore::open(dir.join("store")).unwrap());
+        let c = Arc::new(catalog::open(dir.join("catalog.sqlite")).unwrap());
+        let info = b"d5:filesld6:lengthi3e4:pathl1:a5:b.txteee4:name8:ubuntu\xc3\xa9e".to_vec();
+        let hash = InfoHash::from(sha1::Sha1::from(&info).digest().bytes());
+        store.put(&hash, &info).unwrap();
+        c.announced(&hash).unwrap();
+        c.add(&wire::parse_data(info, hash).unwrap()).unwrap();
+
+        let api = bind("127.0.0.1:0", store).unwrap()
+            .catalog(c)
+            .stats(|| Stats { nodes: 7, ..Default::default() });
+        let addr = api.local_addr().unwrap();
+        api.start();
+
+        let (status, t) = get(addr, &format!("/torrents/{}", hash));
+        assert_eq!(status, 200);
+        assert_eq!(t["name"], "ubuntué");
+        assert_eq!(t["files"][0]["path"], "a/b.txt");
+        assert_eq!(t["announces"], 1);
+
+        let (_, hits) = get(addr, "/search?q=ubuntu%C3%A9&min_files=1");
+        assert_eq!(hits[0]["info_hash"], hash.to_hex());
+        let (_, recent) = get(addr, "/recent?limit=5");
+        assert_eq!(recent.as_array().unwrap().len(), 1);
+        let (_, stats) = get(addr, "/stats");
+        assert_eq!(stats["dht"]["nodes"], 7);
+        assert_eq!(stats["catalogue"]["torrents"], 1);
+
+        assert_eq!(get(addr, &format!("/torrents/{}", InfoHash::default())).0, 404);
+        assert_eq!(get(addr, "/torrents/nope").0, 400);
+        assert_eq!(get(addr, "/search?q=+").0, 400);
+        assert_eq!(get(addr, "/nope").0, 404);
+    }
+
+    #[test]
+    fn query_strings() {
+        let q = parse_query("q=a+b%21&limit=5&x&bad=%zz");
+        assert_eq!(q["q"], "a b!");
+        assert_eq!(q["limit"], "5");
+        assert_eq!(q["x"], "");
+        assert_eq!(q["bad"], "%zz");
+    }
+}
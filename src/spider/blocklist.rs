@@ -0,0 +1,378 @@
+//! Addresses the crawler doesn't talk to: ranges loaded from files, and peers
+//! banned for a while after failing too often.
+//!
+//! Files may mix three formats, one range per line:
+//!
+//! - CIDR or single addresses, IPv4 or IPv6: `10.0.0.0/8`, `2001:db8::1`
+//! - P2P (PeerGuardian): `description:1.2.3.0-1.2.3.255`
+//! - eMule `ipfilter.dat`: `001.002.003.000 - 001.002.003.255 , 100 , description`,
+//!   ranges with an access level above 127 are allowed and skipped; a line is
+//!   only taken as eMule if it starts with a range and a level, P2P
+//!   descriptions may contain commas too
+//!
+//! Blank lines and lines starting with `#` or `//` are ignored.
+//!
+//! Every fetch from a peer breaking the protocol or serving bad data adds to
+//! its penalty, other failures don't count: peers behind a NAT can't be
+//! reached but are fine otherwise. The penalty halves every `half_life_sec`,
+//! and a peer is banned for `ban_sec` when it reaches `ban_threshold`. Bans
+//! only keep the fetchers away, the DHT node only checks the ranges: the
+//! source of a UDP packet may be forged to get anyone banned.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+pub const DEFAULT_BAN_THRESHOLD: f64 = 3.0;
+pub const DEFAULT_HALF_LIFE_SEC: u64 = 600;
+pub const DEFAULT_BAN_SEC: u64 = 3600;
+/// penalty of a peer breaking the protocol or serving bad data
+const PENALTY: f64 = 1.0;
+/// penalties below this are forgotten by `prune`
+const FORGOTTEN_PENALTY: f64 = 0.1;
+/// highest eMule access level that still blocks
+const EMULE_MAX_BLOCKED_LEVEL: u32 = 127;
+/// peers with a penalty at once, new ones are not penalized while it is full
+/// and nothing can be pruned
+const MAX_PENALTIES: usize = 65536;
+
+#[derive(Debug, Clone, Copy)]
+struct Penalty {
+    score: f64,
+    /// when `score` was last decayed
+    at: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl Penalty {
+    fn decay(&mut self, now: Instant, half_life: Duration) {
+        let halvings = now.saturating_duration_since(self.at).as_secs_f64() / half_life.as_secs_f64();
+        self.score *= 0.5f64.powf(halvings);
+        self.at = now;
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|t| now < t)
+    }
+}
+
+pub struct Blocklist {
+    /// sorted, disjoint and inclusive, IPv4 addresses mapped into IPv6
+    ranges: Vec<(u128, u128)>,
+    penalties: Mutex<HashMap<IpAddr, Penalty>>,
+    ban_threshold: f64,
+    half_life: Duration,
+    ban: Duration,
+}
+
+impl Default for Blocklist {
+    fn default() -> Blocklist {
+        Blocklist::new(vec![])
+    }
+}
+
+/// Loads the ranges of every file in `paths`.
+pub fn load(paths: &[String]) -> Result<Blocklist, String> {
+    let mut ranges = vec![];
+    for path in paths {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        ranges.extend(parse(&text).map_err(|e| format!("{}: {}", path, e))?);
+    }
+    let b = Blocklist::new(ranges);
+    if !paths.is_empty() {
+        info!(files = paths.len(), ranges = b.ranges.len(), "loaded the blocklist");
+    }
+    Ok(b)
+}
+
+/// Parses the ranges of a blocklist file, see the module documentation for
+/// the formats.
+pub fn parse(text: &str) -> Result<Vec<(IpAddr, IpAddr)>, String> {
+    let mut ranges = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(Some(r)) => ranges.push(r),
+            Ok(None) => {}
+            Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+        }
+    }
+    Ok(ranges)
+}
+
+/// `None` for an eMule range that allows rather than blocks.
+fn parse_line(line: &str) -> Result<Option<(IpAddr, IpAddr)>, String> {
+    let invalid = || format!("invalid range {:?}", line);
+    let mut range = line;
+    if let Some((r, level)) = split_emule(line) {
+        if level > EMULE_MAX_BLOCKED_LEVEL {
+            return Ok(None);
+        }
+        range = r;
+    }
+    if let Some(i) = range.rfind('-') {
+        // the P2P description may contain anything, addresses don't have `-`
+        let (start, end) = (range[..i].trim(), range[i + 1..].trim());
+        let start = parse_ip(start)
+            .or_else(|| start.rfind(':').and_then(|j| parse_ip(&start[j + 1..])))
+            .ok_or_else(invalid)?;
+        let end = parse_ip(end).ok_or_else(invalid)?;
+        if start.is_ipv4() != end.is_ipv4() || to_u128(start) > to_u128(end) {
+            return Err(invalid());
+        }
+        return Ok(Some((start, end)));
+    }
+    let (addr, len) = match range.split_once('/') {
+        Some((addr, len)) => (addr, Some(len.trim().parse::<u32>().map_err(|_| invalid())?)),
+        None => (range, None),
+    };
+    let addr = parse_ip(addr.trim()).ok_or_else(invalid)?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let len = len.unwrap_or(bits);
+    if len > bits {
+        return Err(invalid());
+    }
+    let host_mask = u128::MAX.checked_shr(128 - (bits - len)).unwrap_or(0);
+    let start = to_u128(addr) & !host_mask;
+    Ok(Some((from_u128(start, addr.is_ipv4()), from_u128(start | host_mask, addr.is_ipv4()))))
+}
+
+/// Range and access level of an eMule line, `None` if `line` has another
+/// format.
+fn split_emule(line: &str) -> Option<(&str, u32)> {
+    let mut fields = line.splitn(3, ',');
+    let range = fields.next()?;
+    let level = fields.next()?.trim().parse().ok()?;
+    let (start, end) = range.split_once('-')?;
+    parse_ip(start.trim())?;
+    parse_ip(end.trim())?;
+    Some((range, level))
+}
+
+/// Also takes IPv4 octets with leading zeros, as eMule writes them.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    if let Ok(ip) = s.parse() {
+        return Some(ip);
+    }
+    let octets: Vec<u8> = s.split('.').map(|o| o.parse().ok()).collect::<Option<_>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn from_u128(n: u128, v4: bool) -> IpAddr {
+    let ip = std::net::Ipv6Addr::from(n);
+    match ip.to_ipv4_mapped() {
+        Some(ip) if v4 => IpAddr::V4(ip),
+        _ => IpAddr::V6(ip),
+    }
+}
+
+impl Blocklist {
+    pub fn new(ranges: Vec<(IpAddr, IpAddr)>) -> Blocklist {
+        let mut sorted: Vec<(u128, u128)> = ranges.into_iter().map(|(s, e)| (to_u128(s), to_u128(e))).collect();
+        sorted.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(sorted.len());
+        for (start, end) in sorted {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Blocklist {
+            ranges: merged,
+            penalties: Mutex::new(HashMap::new()),
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            half_life: Duration::from_secs(DEFAULT_HALF_LIFE_SEC),
+            ban: Duration::from_secs(DEFAULT_BAN_SEC),
+        }
+    }
+
+    pub fn ban_threshold(mut self, t: f64) -> Blocklist {
+        self.ban_threshold = t;
+        self
+    }
+
+    pub fn half_life_sec(mut self, n: u64) -> Blocklist {
+        self.half_life = Duration::from_secs(n.max(1));
+        self
+    }
+
+    pub fn ban_sec(mut self, n: u64) -> Blocklist {
+        self.ban = Duration::from_secs(n);
+        self
+    }
+
+    /// Whether `ip` is in a loaded range or banned.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.is_listed(ip) || self.is_banned_at(ip, Instant::now())
+    }
+
+    /// Whether `ip` is in a loaded range, bans aside.
+    pub fn is_listed(&self, ip: IpAddr) -> bool {
+        let n = to_u128(ip);
+        let i = self.ranges.partition_point(|r| r.0 <= n);
+        i > 0 && n <= self.ranges[i - 1].1
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        self.penalties.lock().unwrap().get(&ip.to_canonical()).is_some_and(|p| p.is_banned(now))
+    }
+
+    /// Counts a misbehaviour of `ip`. True if that got it banned.
+    pub fn penalize(&self, ip: IpAddr) -> bool {
+        self.penalize_at(ip, Instant::now())
+    }
+
+    fn penalize_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut penalties = self.penalties.lock().unwrap();
+        let ip = ip.to_canonical();
+        if penalties.len() >= MAX_PENALTIES && !penalties.contains_key(&ip) {
+            self.prune_locked(&mut penalties, now);
+            if penalties.len() >= MAX_PENALTIES {
+                return false;
+            }
+        }
+        let p = penalties.entry(ip)
+            .or_insert(Penalty { score: 0.0, at: now, banned_until: None });
+        if p.is_banned(now) {
+            return false;
+        }
+        p.decay(now, self.half_life);
+        p.score += PENALTY;
+        if p.score < self.ban_threshold {
+            return false;
+        }
+        p.score = 0.0;
+        p.banned_until = Some(now + self.ban);
+        true
+    }
+
+    /// Number of peers banned right now.
+    pub fn banned(&self) -> usize {
+        let now = Instant::now();
+        self.penalties.lock().unwrap().values().filter(|p| p.is_banned(now)).count()
+    }
+
+    /// Forgets the peers whose ban is over and whose penalty has faded.
+    pub fn prune(&self) {
+        self.prune_at(Instant::now())
+    }
+
+    fn prune_at(&self, now: Instant) {
+        self.prune_locked(&mut self.penalties.lock().unwrap(), now)
+    }
+
+    fn prune_locked(&self, penalties: &mut HashMap<IpAddr, Penalty>, now: Instant) {
+        let half_life = self.half_life;
+        penalties.retain(|_, p| {
+            p.decay(now, half_life);
+            p.is_banned(now) || p.score >= FORGOTTEN_PENALTY
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_formats() {
+        let text = "# comment\n\
+                    // another one\n\
+                    \n\
+                    10.0.0.0/8\n\
+                    192.0.2.7\n\
+                    2001:db8::/32\n\
+                    Some-Corp: bad range:198.51.100.0-198.51.100.255\n\
+                    Foo, Inc:203.0.113.0-203.0.113.255\n\
+                    Bar, 12:203.0.113.0-203.0.113.9\n\
+                    001.002.003.000 - 001.002.003.255 , 100 , blocked\n\
+                    004.005.006.000 - 004.005.006.255 , 200 , allowed\n";
+        assert_eq!(parse(text).unwrap(), vec![
+            (ip("10.0.0.0"), ip("10.255.255.255")),
+            (ip("192.0.2.7"), ip("192.0.2.7")),
+            (ip("2001:db8::"), ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")),
+            (ip("198.51.100.0"), ip("198.51.100.255")),
+            (ip("203.0.113.0"), ip("203.0.113.255")),
+            (ip("203.0.113.0"), ip("203.0.113.9")),
+            (ip("1.2.3.0"), ip("1.2.3.255")),
+        ]);
+        assert_eq!(parse("0.0.0.0/0").unwrap(), vec![(ip("0.0.0.0"), ip("255.255.255.255"))]);
+        assert_eq!(parse("10.0.0.0/8\nnonsense\n").unwrap_err(), "line 2: invalid range \"nonsense\"");
+        assert!(parse("10.0.0.0/33").is_err());
+        assert!(parse("10.0.0.9-10.0.0.1").is_err());
+        assert!(parse("10.0.0.1-::1").is_err());
+        assert!(parse("001.002.003.000 - 001.002.003.255 , high , blocked").is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        let b = Blocklist::new(parse("10.0.0.0/8\n10.1.0.0/16\n11.0.0.0-11.0.0.9\n2001:db8::/32").unwrap());
+        assert_eq!(b.ranges.len(), 2);
+        assert!(b.is_blocked(ip("10.2.3.4")));
+        assert!(b.is_blocked(ip("11.0.0.9")));
+        assert!(b.is_blocked(ip("::ffff:10.0.0.1")));
+        assert!(b.is_blocked(ip("2001:db8::1")));
+        assert!(!b.is_blocked(ip("11.0.0.10")));
+        assert!(!b.is_blocked(ip("9.255.255.255")));
+        assert!(!b.is_blocked(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn penalties() {
+        let b = Blocklist::default().ban_threshold(3.0).half_life_sec(60).ban_sec(600);
+        let (peer, now) = (ip("192.0.2.1"), Instant::now());
+        for _ in 0..2 {
+            assert!(!b.penalize_at(peer, now));
+        }
+        // the penalty has halved after a minute
+        let now = now + Duration::from_secs(60);
+        assert!(!b.penalize_at(peer, now));
+        assert!(b.penalize_at(peer, now));
+        assert!(!b.is_listed(peer));
+        assert!(b.is_banned_at(peer, now + Duration::from_secs(599)));
+        assert!(!b.is_banned_at(peer, now + Duration::from_secs(600)));
+        assert!(!b.is_banned_at(ip("192.0.2.2"), now));
+
+        b.prune_at(now + Duration::from_secs(599));
+        assert_eq!(b.penalties.lock().unwrap().len(), 1);
+        b.prune_at(now + Duration::from_secs(600));
+        assert!(b.penalties.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn penalties_bounded() {
+        let b = Blocklist::default().ban_threshold(PENALTY).half_life_sec(60);
+        let now = Instant::now();
+        for i in 0..MAX_PENALTIES as u32 {
+            b.penalties.lock().unwrap()
+                .insert(IpAddr::V4(Ipv4Addr::from(i)), Penalty { score: PENALTY, at: now, banned_until: None });
+        }
+        let new = ip("2001:db8::1");
+        assert!(!b.penalize_at(new, now));
+        assert_eq!(b.penalties.lock().unwrap().len(), MAX_PENALTIES);
+        assert!(!b.penalties.lock().unwrap().contains_key(&new));
+        // room is made once the old penalties have faded
+        assert!(b.penalize_at(new, now + Duration::from_secs(600)));
+        assert_eq!(b.penalties.lock().unwrap().len(), 1);
+    }
+}
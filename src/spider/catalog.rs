@@ -0,0 +1,337 @@
+//! SQLite catalogue of the torrents seen on the DHT: their metadata once it
+//! is fetched, and when and how often they were announced.
+//!
+//! The schema is created and upgraded by `MIGRATIONS`, the number of applied
+//! ones is kept in `PRAGMA user_version`.
+//!
+//! Names and file paths are indexed for full-text search with the FTS5
+//! trigram tokenizer: it needs no word boundaries, so names mixing scripts
+//! (CJK, Latin, dotted release names) match on any substring. Terms shorter
+//! than three characters can't use the index and are matched with `LIKE`.
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::id::InfoHash;
+use super::wire::{File, Torrent};
+
+/// Applied in order, each one exactly once. Never edit one that shipped,
+/// append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE torrents (
+        info_hash BLOB PRIMARY KEY,
+        name TEXT NOT NULL,
+        length INTEGER NOT NULL,
+        num_files INTEGER NOT NULL,
+        added INTEGER NOT NULL
+    );
+    CREATE TABLE files (
+        info_hash BLOB NOT NULL REFERENCES torrents(info_hash) ON DELETE CASCADE,
+        path TEXT NOT NULL,
+        length INTEGER NOT NULL
+    );
+    CREATE TABLE announces (
+        info_hash BLOB PRIMARY KEY,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        count INTEGER NOT NULL
+    );
+    CREATE INDEX torrents_name ON torrents(name);
+    CREATE INDEX torrents_length ON torrents(length);
+    CREATE INDEX files_info_hash ON files(info_hash);",
+    // the rowid of `search` is the rowid of the torrent
+    "CREATE VIRTUAL TABLE search USING fts5(name, paths, tokenize = 'trigram');
+    INSERT INTO search (rowid, name, paths)
+        SELECT t.rowid, t.name, (SELECT group_concat(f.path, char(10)) FROM files f WHERE f.info_hash = t.info_hash)
+        FROM torrents t;",
+];
+
+/// matches in the name weigh this much more than in a file path
+const NAME_WEIGHT: f64 = 10.0;
+/// shorter terms can't match a trigram index and are scanned for
+const MIN_TERM_CHARS: usize = 3;
+
+/// A catalogued torrent. The times are unix seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub torrent: Torrent,
+    /// when the metadata was fetched
+    pub added: i64,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub announces: i64,
+}
+
+/// A full-text query. Every term must match the name or a file path.
+#[derive(Debug, Clone)]
+pub struct Search {
+    pub text: String,
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    pub min_files: Option<i64>,
+    pub max_files: Option<i64>,
+    pub limit: usize,
+}
+
+impl Default for Search {
+    fn default() -> Search {
+        Search { text: String::new(), min_size: None, max_size: None, min_files: None, max_files: None, limit: 20 }
+    }
+}
+
+/// A search result, best first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub hash: InfoHash,
+    pub name: String,
+    pub length: i64,
+    pub num_files: i64,
+    /// bm25 score, lower is better
+    pub rank: f64,
+}
+
+pub struct Catalog {
+    conn: Mutex<Connection>,
+}
+
+/// Opens or creates the catalogue at `path` and brings its schema up to date.
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Catalog, String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    from_connection(conn)
+}
+
+fn from_connection(mut conn: Connection) -> Result<Catalog, String> {
+    conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;").map_err(|e| e.to_string())?;
+    migrate(&mut conn)?;
+    Ok(Catalog { conn: Mutex::new(conn) })
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0)).map_err(|e| e.to_string())?;
+    if version > MIGRATIONS.len() {
+        return Err(format!("catalogue schema version {} is newer than this binary", version));
+    }
+    for (i, m) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        tx.execute_batch(m).map_err(|e| format!("migration {}: {}", i + 1, e))?;
+        tx.pragma_update(None, "user_version", i + 1).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+}
+
+impl Catalog {
+    /// Counts an announce of `hash`, seen now.
+    pub fn announced(&self, hash: &InfoHash) -> Result<(), String> {
+        let now = now();
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO announces (info_hash, first_seen, last_seen, count) VALUES (?1, ?2, ?2, 1)
+             ON CONFLICT (info_hash) DO UPDATE SET last_seen = ?2, count = count + 1",
+            params![&hash.as_bytes()[..], now],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Saves the metadata of `t`, replacing what was known about it.
+    pub fn add(&self, t: &Torrent) -> Result<(), String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        let hash = &t.hash.as_bytes()[..];
+        tx.execute("DELETE FROM search WHERE rowid = (SELECT rowid FROM torrents WHERE info_hash = ?1)", params![hash])
+            .map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM torrents WHERE info_hash = ?1", params![hash]).map_err(|e| e.to_string())?;
+        tx.execute(
+            "INSERT INTO torrents (info_hash, name, length, num_files, added) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![hash, t.name, t.length, t.files.len() as i64, now()],
+        ).map_err(|e| e.to_string())?;
+        {
+            let mut insert = tx.prepare("INSERT INTO files (info_hash, path, length) VALUES (?1, ?2, ?3)")
+                .map_err(|e| e.to_string())?;
+            for f in t.files.iter() {
+                insert.execute(params![hash, f.name, f.length]).map_err(|e| e.to_string())?;
+            }
+        }
+        let paths: Vec<&str> = t.files.iter().map(|f| f.name.as_str()).collect();
+        tx.execute(
+            "INSERT INTO search (rowid, name, paths) SELECT rowid, ?2, ?3 FROM torrents WHERE info_hash = ?1",
+            params![hash, t.name, paths.join("\n")],
+        ).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Torrents matching `q`, best first. Without a term of three characters
+    /// or more, the matches are unranked, newest first.
+    pub fn search(&self, q: &Search) -> Result<Vec<Hit>, String> {
+        let (long, short): (Vec<&str>, Vec<&str>) = q.text.split_whitespace()
+            .partition(|t| t.chars().count() >= MIN_TERM_CHARS);
+        if long.is_empty() && short.is_empty() {
+            return Err("no search terms".to_string());
+        }
+        // quoted, so that FTS5 operators in names are searched literally
+        let fts: Vec<String> = long.iter().map(|t| format!("\"{}\"", t.replace('"', "\"\""))).collect();
+        let fts = fts.join(" AND ");
+        let likes: Vec<String> = short.iter()
+            .map(|t| format!("%{}%", t.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")))
+            .collect();
+        let (min_size, max_size) = (q.min_size.unwrap_or(0), q.max_size.unwrap_or(i64::MAX));
+        let (min_files, max_files) = (q.min_files.unwrap_or(0), q.max_files.unwrap_or(i64::MAX));
+        let limit = q.limit as i64;
+        let mut args: Vec<&dyn ToSql> = vec![&fts, &NAME_WEIGHT, &min_size, &max_size, &min_files, &max_files, &limit];
+        let mut sql = String::from("SELECT t.info_hash, t.name, t.length, t.num_files, ");
+        sql += if fts.is_empty() { "0.0" } else { "bm25(search, ?2, 1.0)" };
+        sql += " AS rank FROM search JOIN torrents t ON t.rowid = search.rowid
+                 WHERE t.length BETWEEN ?3 AND ?4 AND t.num_files BETWEEN ?5 AND ?6";
+        if !fts.is_empty() {
+            sql += " AND search MATCH ?1";
+        }
+        for like in likes.iter() {
+            args.push(like);
+            sql += &format!(
+                " AND (search.name LIKE ?{0} ESCAPE '\\' OR search.paths LIKE ?{0} ESCAPE '\\')",
+                args.len(),
+            );
+        }
+        sql += if fts.is_empty() { " ORDER BY t.added DESC, t.rowid DESC LIMIT ?7" } else { " ORDER BY rank LIMIT ?7" };
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+        let rows = stmt.query_map(&*args, |r| {
+            let hash: Vec<u8> = r.get(0)?;
+            Ok(Hit {
+                hash: InfoHash::from_bytes(&hash).unwrap_or_default(),
+                name: r.get(1)?,
+                length: r.get(2)?,
+                num_files: r.get(3)?,
+                rank: r.get(4)?,
+            })
+        }).map_err(|e| e.to_string())?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
+    }
+
+    /// The `limit` torrents fetched last, newest first.
+    pub fn recent(&self, limit: usize) -> Result<Vec<Record>, String> {
+        let hashes: Vec<Vec<u8>> = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("SELECT info_hash FROM torrents ORDER BY added DESC, rowid DESC LIMIT ?1")
+                .map_err(|e| e.to_string())?;
+            let rows = stmt.query_map(params![limit as i64], |r| r.get(0)).map_err(|e| e.to_string())?;
+            rows.collect::<Result<_, _>>().map_err(|e| e.to_string())?
+        };
+        let mut records = vec![];
+        for h in hashes.iter().filter_map(|h| InfoHash::from_bytes(h)) {
+            if let Some(r) = self.get(&h)? {
+                records.push(r);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Number of torrents catalogued.
+    pub fn count(&self) -> Result<i64, String> {
+        self.conn.lock().unwrap()
+            .query_row("SELECT count(*) FROM torrents", [], |r| r.get(0))
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn get(&self, hash: &InfoHash) -> Result<Option<Record>, String> {
+        let conn = self.conn.lock().unwrap();
+        let record = conn.query_row(
+            "SELECT t.name, t.length, t.added, a.first_seen, a.last_seen, a.count
+             FROM torrents t LEFT JOIN announces a ON a.info_hash = t.info_hash
+             WHERE t.info_hash = ?1",
+            params![&hash.as_bytes()[..]],
+            |r| {
+                Ok(Record {
+                    torrent: Torrent { hash: *hash, name: r.get(0)?, length: r.get(1)?, files: vec![] },
+                    added: r.get(2)?,
+                    first_seen: r.get::<_, Option<i64>>(3)?.unwrap_or_default(),
+                    last_seen: r.get::<_, Option<i64>>(4)?.unwrap_or_default(),
+                    announces: r.get::<_, Option<i64>>(5)?.unwrap_or_default(),
+                })
+            },
+        ).optional().map_err(|e| e.to_string())?;
+        let mut record = match record {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let mut files = conn.prepare("SELECT path, length FROM files WHERE info_hash = ?1 ORDER BY rowid")
+            .map_err(|e| e.to_string())?;
+        let files = files.query_map(params![&hash.as_bytes()[..]], |r| Ok(File { name: r.get(0)?, length: r.get(1)? }))
+            .map_err(|e| e.to_string())?;
+        for f in files {
+            record.torrent.files.push(f.map_err(|e| e.to_string())?);
+        }
+        Ok(Some(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::torrent;
+
+    #[test]
+    fn add_and_get() {
+        let c = from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let t = Torrent {
+            hash: InfoHash::random(),
+            name: "a".to_string(),
+            length: 3,
+            files: vec![
+                File { name: "a/x".to_string(), length: 1 },
+                File { name: "a/y".to_string(), length: 2 },
+            ],
+        };
+        assert_eq!(c.get(&t.hash), Ok(None));
+        c.announced(&t.hash).unwrap();
+        c.announced(&t.hash).unwrap();
+        c.add(&t).unwrap();
+        c.add(&t).unwrap();
+        let r = c.get(&t.hash).unwrap().unwrap();
+        assert_eq!(r.torrent, t);
+        assert_eq!(r.announces, 2);
+        assert!(r.first_seen > 0 && r.first_seen <= r.last_seen);
+
+        // reopening must not run the migrations again
+        let mut conn = c.conn.into_inner().unwrap();
+        migrate(&mut conn).unwrap();
+        let version: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn search() {
+        let c = from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let ubuntu = torrent("Ubuntu.22.04.Desktop", &[("ubuntu-22.04-desktop-amd64.iso", 3000)]);
+        let jp = torrent("日本語のアニメ 第01話", &[("アニメ/第01話.mkv", 500), ("アニメ/readme.txt", 1)]);
+        let docs = torrent("docs", &[("notes/ubuntu install.txt", 10)]);
+        for t in [&ubuntu, &jp, &docs].iter() {
+            c.add(t).unwrap();
+        }
+        c.add(&ubuntu).unwrap();
+
+        let names = |q: &Search| -> Vec<String> { c.search(q).unwrap().into_iter().map(|h| h.name).collect() };
+        let q = |text: &str| Search { text: text.to_string(), ..Default::default() };
+        // a name match ranks above a path match
+        assert_eq!(names(&q("UBUNTU")), vec![ubuntu.name.clone(), docs.name.clone()]);
+        assert_eq!(names(&q("アニメ 第01話")), vec![jp.name.clone()]);
+        assert_eq!(names(&q("desktop amd64")), vec![ubuntu.name.clone()]);
+        assert_eq!(names(&q("\"ubuntu\" OR")), Vec::<String>::new());
+        assert_eq!(names(&Search { max_size: Some(100), ..q("ubuntu") }), vec![docs.name.clone()]);
+        assert_eq!(names(&Search { min_files: Some(2), ..q("txt") }), vec![jp.name.clone()]);
+        // shorter terms are looked for too, alone or next to longer ones
+        assert_eq!(names(&q("日本")), vec![jp.name.clone()]);
+        assert_eq!(names(&q("日本 01")), vec![jp.name.clone()]);
+        assert_eq!(names(&q("ubuntu 22")), vec![ubuntu.name.clone()]);
+        assert_eq!(names(&q("ubuntu 日本")), Vec::<String>::new());
+        assert_eq!(names(&q("%")), Vec::<String>::new());
+        assert!(c.search(&q(" ")).is_err());
+    }
+}
@@ -0,0 +1,694 @@
+extern crate serde;
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+
+use self::serde_derive::Deserialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::net;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::api;
+use super::blocklist::{self, Blocklist};
+use super::catalog;
+use super::catalog::Catalog;
+use super::dedupe::{self, Dedupe};
+use super::dht;
+use super::id::{InfoHash, NodeId};
+use super::logging;
+use super::metrics::{self, Metrics};
+use super::rules;
+use super::store::db::DbStore;
+use super::store::fs::FsStore;
+use super::store::TorrentStore;
+use super::wire;
+use super::wire::seeder;
+
+const ENV_PREFIX: &str = "P2PSPIDER_";
+
+/// Settings of one crawler instance.
+///
+/// Values are taken from the built-in defaults, then from the config file, then
+/// from `P2PSPIDER_*` environment variables, the last one winning.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// directory or database of the torrent store, depending on its backend
+    pub output: String,
+    pub store: StoreSettings,
+    pub catalog: CatalogSettings,
+    pub dedupe: DedupeSettings,
+    pub blocklist: BlocklistSettings,
+    pub rules: RulesSettings,
+    pub dht: DhtSettings,
+    pub wire: WireSettings,
+    pub seeder: SeederSettings,
+    pub api: ApiSettings,
+    pub metrics: MetricsSettings,
+    pub log: LogSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DhtSettings {
+    pub bind: String,
+    /// address of the IPv6 node, none is started if empty
+    pub bind6: String,
+    /// 40 hex characters, random if empty
+    pub id: String,
+    /// find_node queries sent to discover nodes
+    pub max_friends_per_sec: u32,
+    /// replies to queries, more are dropped
+    pub max_replies_per_sec: u32,
+    /// pings of questionable nodes
+    pub max_pings_per_sec: u32,
+    /// cap of everything a node sends, none if 0
+    pub max_bytes_per_sec: u64,
+    /// mixed into the announce tokens along with a random secret
+    pub secret: String,
+    /// seconds between changes of the random secret, a token stays valid for
+    /// one to two of them
+    pub token_rotate_sec: u64,
+    pub bootstraps: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WireSettings {
+    /// number of concurrent metadata fetches
+    pub workers: usize,
+    /// announces waiting for a worker, newer ones are dropped when full
+    pub queue_len: usize,
+    pub connect_timeout_sec: i32,
+    pub handshake_timeout_sec: i32,
+    /// whole fetch, including the connect and the handshakes
+    pub timeout_sec: i32,
+    pub max_metadata_size: i32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreSettings {
+    /// `fs` for one file per torrent, `db` for an embedded database
+    pub backend: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CatalogSettings {
+    /// SQLite database of the crawled torrents, relative to `output`,
+    /// disabled if empty
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupeSettings {
+    /// file the filter of fetched info hashes is saved to, relative to
+    /// `output`, kept in memory only if empty
+    pub path: String,
+    /// info hashes the filter is sized for
+    pub capacity: u64,
+    pub false_positive_rate: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocklistSettings {
+    /// files of ranges never talked to, in CIDR, P2P or eMule format
+    pub files: Vec<String>,
+    /// penalty that gets a peer banned, each fetch from a misbehaving peer
+    /// counts 1
+    pub ban_threshold: f64,
+    /// seconds for a penalty to halve
+    pub half_life_sec: u64,
+    pub ban_sec: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesSettings {
+    /// TOML file of the info hashes and torrents to drop, disabled if empty
+    pub path: String,
+    /// seconds between checks for changes of the file
+    pub reload_sec: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SeederSettings {
+    /// TCP address serving the fetched metadata, disabled if empty
+    pub bind: String,
+    pub max_connections: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiSettings {
+    /// HTTP address of the JSON API, disabled if empty
+    pub bind: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSettings {
+    /// HTTP address of the Prometheus endpoint, disabled if empty
+    pub bind: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    /// `text`, `pretty` or `json`
+    pub format: String,
+    /// which events are logged, e.g. `info,p2pspider::spider::dht=debug`
+    pub filter: String,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            output: ".".to_string(),
+            store: StoreSettings::default(),
+            catalog: CatalogSettings::default(),
+            dedupe: DedupeSettings::default(),
+            blocklist: BlocklistSettings::default(),
+            rules: RulesSettings::default(),
+            dht: DhtSettings::default(),
+            wire: WireSettings::default(),
+            seeder: SeederSettings::default(),
+            api: ApiSettings::default(),
+            metrics: MetricsSettings::default(),
+            log: LogSettings::default(),
+        }
+    }
+}
+
+impl Default for DhtSettings {
+    fn default() -> DhtSettings {
+        DhtSettings {
+            bind: dht::DEFAULT_ADDR.to_string(),
+            bind6: dht::DEFAULT_ADDR6.to_string(),
+            id: String::new(),
+            max_friends_per_sec: dht::ratelimit::DEFAULT_FRIENDS_PER_SEC,
+            max_replies_per_sec: dht::ratelimit::DEFAULT_REPLIES_PER_SEC,
+            max_pings_per_sec: dht::ratelimit::DEFAULT_PINGS_PER_SEC,
+            max_bytes_per_sec: 0,
+            secret: dht::DEFAULT_SECRET.to_string(),
+            token_rotate_sec: dht::token::DEFAULT_ROTATE_SEC,
+            bootstraps: dht::BOOTSTRAP_NODES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl Default for WireSettings {
+    fn default() -> WireSettings {
+        WireSettings {
+            workers: 16,
+            queue_len: 256,
+            connect_timeout_sec: wire::DEFAULT_CONNECT_TIMEOUT_SEC,
+            handshake_timeout_sec: wire::DEFAULT_HANDSHAKE_TIMEOUT_SEC,
+            timeout_sec: wire::DEFAULT_TIMEOUT_SEC,
+            max_metadata_size: wire::MAX_META_DATA_SIZE,
+        }
+    }
+}
+
+impl Default for StoreSettings {
+    fn default() -> StoreSettings {
+        StoreSettings { backend: "fs".to_string() }
+    }
+}
+
+impl Default for LogSettings {
+    fn default() -> LogSettings {
+        LogSettings {
+            format: logging::DEFAULT_FORMAT.to_string(),
+            filter: logging::DEFAULT_FILTER.to_string(),
+        }
+    }
+}
+
+impl Default for CatalogSettings {
+    fn default() -> CatalogSettings {
+        CatalogSettings { path: "catalog.sqlite".to_string() }
+    }
+}
+
+impl Default for DedupeSettings {
+    fn default() -> DedupeSettings {
+        DedupeSettings {
+            path: "seen.bloom".to_string(),
+            capacity: dedupe::DEFAULT_CAPACITY,
+            false_positive_rate: dedupe::DEFAULT_FALSE_POSITIVE_RATE,
+        }
+    }
+}
+
+impl Default for BlocklistSettings {
+    fn default() -> BlocklistSettings {
+        BlocklistSettings {
+            files: vec![],
+            ban_threshold: blocklist::DEFAULT_BAN_THRESHOLD,
+            half_life_sec: blocklist::DEFAULT_HALF_LIFE_SEC,
+            ban_sec: blocklist::DEFAULT_BAN_SEC,
+        }
+    }
+}
+
+impl Default for RulesSettings {
+    fn default() -> RulesSettings {
+        RulesSettings { path: String::new(), reload_sec: rules::DEFAULT_RELOAD_SEC }
+    }
+}
+
+impl Default for SeederSettings {
+    fn default() -> SeederSettings {
+        SeederSettings {
+            bind: String::new(),
+            max_connections: seeder::DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+}
+
+/// Loads the settings from `path`, JSON if the file name ends with `.json` and
+/// TOML otherwise, applies the environment overrides and validates the result.
+pub fn load(path: &str) -> Result<Settings, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let is_json = Path::new(path).extension().is_some_and(|e| e == "json");
+    let mut s: Settings = if is_json {
+        serde_json::from_str(&content).map_err(|e| format!("{}: {}", path, e))?
+    } else {
+        toml::from_str(&content).map_err(|e| format!("{}: {}", path, e))?
+    };
+    s.apply_env()?;
+    s.validate()?;
+    Ok(s)
+}
+
+/// Built-in defaults with the environment overrides applied.
+pub fn from_env() -> Result<Settings, String> {
+    let mut s = Settings::default();
+    s.apply_env()?;
+    s.validate()?;
+    Ok(s)
+}
+
+impl Settings {
+    /// Overrides every key that has a matching environment variable, e.g.
+    /// `dht.max_friends_per_sec` is read from `P2PSPIDER_DHT_MAX_FRIENDS_PER_SEC`.
+    /// `dht.bootstraps` and `blocklist.files` are comma separated lists.
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        env_string("OUTPUT", &mut self.output);
+        env_string("STORE_BACKEND", &mut self.store.backend);
+        env_string("CATALOG_PATH", &mut self.catalog.path);
+        env_string("DEDUPE_PATH", &mut self.dedupe.path);
+        env_parse("DEDUPE_CAPACITY", &mut self.dedupe.capacity)?;
+        env_parse("DEDUPE_FALSE_POSITIVE_RATE", &mut self.dedupe.false_positive_rate)?;
+        env_list("BLOCKLIST_FILES", &mut self.blocklist.files);
+        env_parse("BLOCKLIST_BAN_THRESHOLD", &mut self.blocklist.ban_threshold)?;
+        env_parse("BLOCKLIST_HALF_LIFE_SEC", &mut self.blocklist.half_life_sec)?;
+        env_parse("BLOCKLIST_BAN_SEC", &mut self.blocklist.ban_sec)?;
+        env_string("RULES_PATH", &mut self.rules.path);
+        env_parse("RULES_RELOAD_SEC", &mut self.rules.reload_sec)?;
+        env_string("DHT_BIND", &mut self.dht.bind);
+        env_string("DHT_BIND6", &mut self.dht.bind6);
+        env_string("DHT_ID", &mut self.dht.id);
+        env_parse("DHT_MAX_FRIENDS_PER_SEC", &mut self.dht.max_friends_per_sec)?;
+        env_parse("DHT_MAX_REPLIES_PER_SEC", &mut self.dht.max_replies_per_sec)?;
+        env_parse("DHT_MAX_PINGS_PER_SEC", &mut self.dht.max_pings_per_sec)?;
+        env_parse("DHT_MAX_BYTES_PER_SEC", &mut self.dht.max_bytes_per_sec)?;
+        env_string("DHT_SECRET", &mut self.dht.secret);
+        env_parse("DHT_TOKEN_ROTATE_SEC", &mut self.dht.token_rotate_sec)?;
+        env_list("DHT_BOOTSTRAPS", &mut self.dht.bootstraps);
+        env_parse("WIRE_WORKERS", &mut self.wire.workers)?;
+        env_parse("WIRE_QUEUE_LEN", &mut self.wire.queue_len)?;
+        env_parse("WIRE_CONNECT_TIMEOUT_SEC", &mut self.wire.connect_timeout_sec)?;
+        env_parse("WIRE_HANDSHAKE_TIMEOUT_SEC", &mut self.wire.handshake_timeout_sec)?;
+        env_parse("WIRE_TIMEOUT_SEC", &mut self.wire.timeout_sec)?;
+        env_parse("WIRE_MAX_METADATA_SIZE", &mut self.wire.max_metadata_size)?;
+        env_string("SEEDER_BIND", &mut self.seeder.bind);
+        env_parse("SEEDER_MAX_CONNECTIONS", &mut self.seeder.max_connections)?;
+        env_string("API_BIND", &mut self.api.bind);
+        env_string("METRICS_BIND", &mut self.metrics.bind);
+        env_string("LOG_FORMAT", &mut self.log.format);
+        env_string("LOG_FILTER", &mut self.log.filter);
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.output.is_empty() {
+            return Err("output: must not be empty".to_string());
+        }
+        if self.store.backend != "fs" && self.store.backend != "db" {
+            return Err(format!("store.backend: expected \"fs\" or \"db\", got {:?}", self.store.backend));
+        }
+        if net::SocketAddr::from_str(&self.dht.bind).is_err() {
+            return Err(format!("dht.bind: invalid socket address {:?}", self.dht.bind));
+        }
+        match net::SocketAddr::from_str(&self.dht.bind6) {
+            Ok(a) if !a.is_ipv6() => return Err(format!("dht.bind6: {:?} is not an IPv6 address", self.dht.bind6)),
+            Err(_) if !self.dht.bind6.is_empty() => return Err(format!("dht.bind6: invalid socket address {:?}", self.dht.bind6)),
+            _ => {}
+        }
+        if !self.dht.id.is_empty() && NodeId::from_hex(&self.dht.id).is_none() {
+            return Err(format!("dht.id: expected 40 hex characters, got {:?}", self.dht.id));
+        }
+        if self.dht.max_friends_per_sec < 1 || self.dht.max_friends_per_sec > 1000 {
+            return Err(format!("dht.max_friends_per_sec: {} is out of range 1..1000", self.dht.max_friends_per_sec));
+        }
+        if self.dht.max_replies_per_sec < 1 {
+            return Err("dht.max_replies_per_sec: must be positive".to_string());
+        }
+        if self.dht.max_pings_per_sec < 1 {
+            return Err("dht.max_pings_per_sec: must be positive".to_string());
+        }
+        if self.dht.secret.is_empty() {
+            return Err("dht.secret: must not be empty".to_string());
+        }
+        if self.dht.token_rotate_sec < 1 {
+            return Err("dht.token_rotate_sec: must be positive".to_string());
+        }
+        if self.dht.bootstraps.is_empty() {
+            return Err("dht.bootstraps: at least one node is required".to_string());
+        }
+        for b in self.dht.bootstraps.iter() {
+            match b.rfind(':').map(|i| b[i + 1..].parse::<u16>()) {
+                Some(Ok(_)) => {}
+                _ => return Err(format!("dht.bootstraps: expected host:port, got {:?}", b)),
+            }
+        }
+        if self.wire.workers < 1 {
+            return Err("wire.workers: at least one worker is required".to_string());
+        }
+        if self.wire.queue_len < 1 {
+            return Err("wire.queue_len: must be positive".to_string());
+        }
+        if self.wire.connect_timeout_sec < 1 {
+            return Err(format!("wire.connect_timeout_sec: {} must be positive", self.wire.connect_timeout_sec));
+        }
+        if self.wire.handshake_timeout_sec < 1 {
+            return Err(format!("wire.handshake_timeout_sec: {} must be positive", self.wire.handshake_timeout_sec));
+        }
+        if self.wire.timeout_sec < 1 {
+            return Err(format!("wire.timeout_sec: {} must be positive", self.wire.timeout_sec));
+        }
+        if self.wire.max_metadata_size < 1 {
+            return Err(format!("wire.max_metadata_size: {} must be positive", self.wire.max_metadata_size));
+        }
+        if self.dedupe.capacity < 1 {
+            return Err("dedupe.capacity: must be positive".to_string());
+        }
+        if !(self.dedupe.false_positive_rate > 0.0 && self.dedupe.false_positive_rate < 1.0) {
+            return Err("dedupe.false_positive_rate: must be between 0 and 1".to_string());
+        }
+        if !(self.blocklist.ban_threshold > 0.0 && self.blocklist.ban_threshold.is_finite()) {
+            return Err("blocklist.ban_threshold: must be positive".to_string());
+        }
+        if self.blocklist.half_life_sec < 1 {
+            return Err("blocklist.half_life_sec: must be positive".to_string());
+        }
+        if self.rules.reload_sec < 1 {
+            return Err("rules.reload_sec: must be positive".to_string());
+        }
+        if !self.seeder.bind.is_empty() && net::SocketAddr::from_str(&self.seeder.bind).is_err() {
+            return Err(format!("seeder.bind: invalid socket address {:?}", self.seeder.bind));
+        }
+        if self.seeder.max_connections < 1 {
+            return Err("seeder.max_connections: must be positive".to_string());
+        }
+        if !self.api.bind.is_empty() && net::SocketAddr::from_str(&self.api.bind).is_err() {
+            return Err(format!("api.bind: invalid socket address {:?}", self.api.bind));
+        }
+        if !self.metrics.bind.is_empty() && net::SocketAddr::from_str(&self.metrics.bind).is_err() {
+            return Err(format!("metrics.bind: invalid socket address {:?}", self.metrics.bind));
+        }
+        if !logging::FORMATS.contains(&self.log.format.as_str()) {
+            return Err(format!("log.format: expected one of {:?}, got {:?}", logging::FORMATS, self.log.format));
+        }
+        Ok(())
+    }
+
+    /// Binds a `RustDHT` configured from the `dht` section.
+    pub fn dht(&self) -> Result<dht::RustDHT, dht::DhtError> {
+        self.bind_dht(&self.dht.bind)
+    }
+
+    /// Binds the IPv6 `RustDHT`, `None` if `dht.bind6` is empty.
+    pub fn dht6(&self) -> Option<Result<dht::RustDHT, dht::DhtError>> {
+        if self.dht.bind6.is_empty() {
+            return None;
+        }
+        Some(self.bind_dht(&self.dht.bind6))
+    }
+
+    fn bind_dht(&self, addr: &str) -> Result<dht::RustDHT, dht::DhtError> {
+        let mut d = dht::bind(addr)?
+            .max_friends_per_sec(self.dht.max_friends_per_sec)
+            .max_replies_per_sec(self.dht.max_replies_per_sec)
+            .max_pings_per_sec(self.dht.max_pings_per_sec)
+            .max_bytes_per_sec(self.dht.max_bytes_per_sec)
+            .secret(self.dht.secret.clone())
+            .token_rotate_sec(self.dht.token_rotate_sec)
+            .bootstraps(self.dht.bootstraps.clone());
+        if let Some(id) = NodeId::from_hex(&self.dht.id) {
+            d = d.local_id(id);
+        }
+        Ok(d)
+    }
+
+    /// Opens the torrent store at `output`.
+    pub fn store(&self) -> Result<Arc<dyn TorrentStore>, String> {
+        let err = |e: String| format!("{}: {}", self.output, e);
+        if self.store.backend == "db" {
+            return Ok(Arc::new(DbStore::open(&self.output).map_err(err)?));
+        }
+        Ok(Arc::new(FsStore::open(&self.output).map_err(|e| err(e.to_string()))?))
+    }
+
+    /// Opens the catalogue, `None` if `catalog.path` is empty.
+    pub fn catalog(&self) -> Option<Result<Catalog, String>> {
+        if self.catalog.path.is_empty() {
+            return None;
+        }
+        let path = self.in_output(&self.catalog.path);
+        Some(catalog::open(&path).map_err(|e| format!("{}: {}", path, e)))
+    }
+
+    /// Loads or builds the filter of the info hashes in `store`.
+    pub fn dedupe(&self, store: Arc<dyn TorrentStore>) -> Result<Dedupe, String> {
+        let path = self.in_output(&self.dedupe.path);
+        dedupe::open(&path, self.dedupe.capacity, self.dedupe.false_positive_rate, store)
+    }
+
+    /// `path` relative to `output` unless it is absolute, empty stays empty.
+    fn in_output(&self, path: &str) -> String {
+        if path.is_empty() {
+            return String::new();
+        }
+        Path::new(&self.output).join(path).to_string_lossy().into_owned()
+    }
+
+    /// Loads the ranges of `blocklist.files`, with the ban settings.
+    pub fn blocklist(&self) -> Result<Blocklist, String> {
+        Ok(blocklist::load(&self.blocklist.files)?
+            .ban_threshold(self.blocklist.ban_threshold)
+            .half_life_sec(self.blocklist.half_life_sec)
+            .ban_sec(self.blocklist.ban_sec))
+    }
+
+    /// Loads the moderation rules, `None` if `rules.path` is empty.
+    pub fn rules(&self) -> Option<Result<rules::Filter, String>> {
+        if self.rules.path.is_empty() {
+            return None;
+        }
+        Some(rules::open(&self.rules.path))
+    }
+
+    /// Binds the metadata `Seeder`, `None` if `seeder.bind` is empty.
+    pub fn seeder(&self) -> Option<Result<seeder::Seeder, io::Error>> {
+        if self.seeder.bind.is_empty() {
+            return None;
+        }
+        Some(seeder::bind(&self.seeder.bind).map(|s| s.max_connections(self.seeder.max_connections)))
+    }
+
+    /// Binds the HTTP API serving `store`, `None` if `api.bind` is empty.
+    pub fn api(&self, store: Arc<dyn TorrentStore>) -> Option<Result<api::Api, io::Error>> {
+        if self.api.bind.is_empty() {
+            return None;
+        }
+        Some(api::bind(&self.api.bind, store))
+    }
+
+    /// Binds the Prometheus endpoint serving `m`, `None` if `metrics.bind` is
+    /// empty.
+    pub fn metrics(&self, m: Arc<Metrics>) -> Option<Result<metrics::Exporter, io::Error>> {
+        if self.metrics.bind.is_empty() {
+            return None;
+        }
+        Some(metrics::bind(&self.metrics.bind, m))
+    }
+
+    /// Sends the log events to stderr, see `logging`.
+    pub fn init_logging(&self) -> Result<(), String> {
+        logging::init(&self.log.format, &self.log.filter)
+    }
+
+    /// Connects a `Wire` configured from the `wire` section.
+    pub fn wire(&self, info: InfoHash, from: String) -> Result<wire::Wire, wire::WireError> {
+        Ok(wire::connect(info, from, self.wire.connect_timeout_sec)?
+            .handshake_timeout_sec(self.wire.handshake_timeout_sec)
+            .timeout_sec(self.wire.timeout_sec)
+            .max_metadata_size(self.wire.max_metadata_size))
+    }
+}
+
+fn env_string(key: &str, v: &mut String) {
+    if let Ok(s) = env::var(format!("{}{}", ENV_PREFIX, key)) {
+        *v = s;
+    }
+}
+
+fn env_list(key: &str, v: &mut Vec<String>) {
+    if let Ok(s) = env::var(format!("{}{}", ENV_PREFIX, key)) {
+        *v = s.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+    }
+}
+
+fn env_parse<T: FromStr>(key: &str, v: &mut T) -> Result<(), String> {
+    let key = format!("{}{}", ENV_PREFIX, key);
+    if let Ok(s) = env::var(&key) {
+        *v = s.parse().map_err(|_| format!("{}: invalid value {:?}", key, s))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::TempDir;
+    use std::sync::Mutex;
+
+    /// the environment is shared by the tests running in parallel
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn write(dir: &TempDir, name: &str, content: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn load_files() {
+        let _env = ENV.lock().unwrap();
+        let dir = TempDir::new("config");
+        let path = write(&dir, "p2pspider.toml", "output = \"torrents\"\n[dht]\nmax_friends_per_sec = 10\n");
+        let s = load(&path).unwrap();
+        assert_eq!(s.output, "torrents");
+        assert_eq!(s.dht.max_friends_per_sec, 10);
+        // missing keys keep their default
+        assert_eq!(s.dht.bind, dht::DEFAULT_ADDR);
+        assert_eq!(s.wire.workers, WireSettings::default().workers);
+
+        let path = write(&dir, "p2pspider.json", r#"{"output": "torrents", "wire": {"workers": 4}}"#);
+        let s = load(&path).unwrap();
+        assert_eq!((s.output.as_str(), s.wire.workers), ("torrents", 4));
+
+        let path = write(&dir, "unknown.toml", "[dht]\nmax_friend_per_sec = 10\n");
+        assert!(load(&path).unwrap_err().contains("max_friend_per_sec"));
+        let path = write(&dir, "invalid.toml", "[wire]\nworkers = 0\n");
+        assert!(load(&path).unwrap_err().starts_with("wire.workers:"));
+        assert!(load("/nonexistent/p2pspider.toml").is_err());
+
+        let example = format!("{}/config.example.toml", env!("CARGO_MANIFEST_DIR"));
+        load(&example).unwrap();
+    }
+
+    #[test]
+    fn env_overrides() {
+        let _env = ENV.lock().unwrap();
+        let dir = TempDir::new("config");
+        let path = write(&dir, "p2pspider.toml", "output = \"from-file\"\n[dht]\nmax_friends_per_sec = 10\n");
+        env::set_var("P2PSPIDER_OUTPUT", "from-env");
+        env::set_var("P2PSPIDER_DHT_BOOTSTRAPS", " a.example:6881, b.example:6881 ,,");
+        env::set_var("P2PSPIDER_WIRE_WORKERS", "3");
+        let s = load(&path);
+        let invalid = {
+            env::set_var("P2PSPIDER_WIRE_WORKERS", "many");
+            from_env()
+        };
+        for key in ["OUTPUT", "DHT_BOOTSTRAPS", "WIRE_WORKERS"] {
+            env::remove_var(format!("{}{}", ENV_PREFIX, key));
+        }
+
+        let s = s.unwrap();
+        assert_eq!(s.output, "from-env");
+        assert_eq!(s.dht.max_friends_per_sec, 10);
+        assert_eq!(s.dht.bootstraps, vec!["a.example:6881", "b.example:6881"]);
+        assert_eq!(s.wire.workers, 3);
+        assert_eq!(invalid.unwrap_err(), "P2PSPIDER_WIRE_WORKERS: invalid value \"many\"");
+    }
+
+    #[test]
+    fn paths() {
+        let s = Settings { output: "torrents".to_string(), ..Default::default() };
+        assert_eq!(s.in_output(&s.catalog.path), Path::new("torrents").join("catalog.sqlite").to_string_lossy());
+        assert_eq!(s.in_output(&s.dedupe.path), Path::new("torrents").join("seen.bloom").to_string_lossy());
+        assert_eq!(s.in_output("/var/lib/p2pspider/seen.bloom"), "/var/lib/p2pspider/seen.bloom");
+        assert_eq!(s.in_output(""), "");
+    }
+
+    #[test]
+    fn validate() {
+        Settings::default().validate().unwrap();
+        type Change = fn(&mut Settings);
+        let cases: Vec<(&str, Change)> = vec![
+            ("output:", |s| s.output.clear()),
+            ("store.backend:", |s| s.store.backend = "sql".to_string()),
+            ("dht.bind:", |s| s.dht.bind = "localhost".to_string()),
+            ("dht.bind6:", |s| s.dht.bind6 = "0.0.0.0:1".to_string()),
+            ("dht.bind6:", |s| s.dht.bind6 = "::1".to_string()),
+            ("dht.id:", |s| s.dht.id = "abc".to_string()),
+            ("dht.max_friends_per_sec:", |s| s.dht.max_friends_per_sec = 0),
+            ("dht.max_friends_per_sec:", |s| s.dht.max_friends_per_sec = 1001),
+            ("dht.max_replies_per_sec:", |s| s.dht.max_replies_per_sec = 0),
+            ("dht.max_pings_per_sec:", |s| s.dht.max_pings_per_sec = 0),
+            ("dht.secret:", |s| s.dht.secret.clear()),
+            ("dht.token_rotate_sec:", |s| s.dht.token_rotate_sec = 0),
+            ("dht.bootstraps:", |s| s.dht.bootstraps.clear()),
+            ("dht.bootstraps:", |s| s.dht.bootstraps = vec!["router.example".to_string()]),
+            ("wire.workers:", |s| s.wire.workers = 0),
+            ("wire.queue_len:", |s| s.wire.queue_len = 0),
+            ("wire.connect_timeout_sec:", |s| s.wire.connect_timeout_sec = 0),
+            ("wire.handshake_timeout_sec:", |s| s.wire.handshake_timeout_sec = -1),
+            ("wire.timeout_sec:", |s| s.wire.timeout_sec = 0),
+            ("wire.max_metadata_size:", |s| s.wire.max_metadata_size = 0),
+            ("dedupe.capacity:", |s| s.dedupe.capacity = 0),
+            ("dedupe.false_positive_rate:", |s| s.dedupe.false_positive_rate = 1.0),
+            ("dedupe.false_positive_rate:", |s| s.dedupe.false_positive_rate = f64::NAN),
+            ("blocklist.ban_threshold:", |s| s.blocklist.ban_threshold = 0.0),
+            ("blocklist.ban_threshold:", |s| s.blocklist.ban_threshold = f64::INFINITY),
+            ("blocklist.half_life_sec:", |s| s.blocklist.half_life_sec = 0),
+            ("rules.reload_sec:", |s| s.rules.reload_sec = 0),
+            ("seeder.bind:", |s| s.seeder.bind = "nowhere".to_string()),
+            ("seeder.max_connections:", |s| s.seeder.max_connections = 0),
+            ("api.bind:", |s| s.api.bind = "nowhere".to_string()),
+            ("metrics.bind:", |s| s.metrics.bind = "nowhere".to_string()),
+            ("log.format:", |s| s.log.format = "xml".to_string()),
+        ];
+        for (prefix, change) in cases {
+            let mut s = Settings::default();
+            change(&mut s);
+            let e = s.validate().unwrap_err();
+            assert!(e.starts_with(prefix), "expected {}, got {}", prefix, e);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Skips the announces of torrents we already hold or are already fetching.
+//!
+//! A Bloom filter of the info hashes in the store answers most announces
+//! without touching the disk. It never misses a hash it was given, and its
+//! rare false positives are checked against the store, so nothing is skipped
+//! that wasn't fetched. The filter is saved to a file and loaded back on
+//! start; without the file it is rebuilt from the store.
+
+use std::collections::HashSet;
+use std::f64::consts::LN_2;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use tracing::{info, warn};
+
+use super::id::InfoHash;
+use super::store::TorrentStore;
+
+pub const DEFAULT_CAPACITY: u64 = 10_000_000;
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.001;
+const MAGIC: &[u8; 8] = b"p2psblm1";
+const MAX_HASHES: u32 = 16;
+
+/// Bloom filter of info hashes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bloom {
+    bits: Vec<u64>,
+    /// bits set per hash
+    k: u32,
+}
+
+impl Bloom {
+    /// Sized to hold `capacity` hashes with about `fp_rate` false positives.
+    pub fn new(capacity: u64, fp_rate: f64) -> Bloom {
+        let n = capacity.max(1) as f64;
+        let m = (-n * fp_rate.ln() / (LN_2 * LN_2)).ceil().max(64.0);
+        let k = ((m / n) * LN_2).round().clamp(1.0, MAX_HASHES as f64) as u32;
+        Bloom { bits: vec![0; (m as usize).div_ceil(64)], k }
+    }
+
+    pub fn insert(&mut self, h: &InfoHash) {
+        for i in self.indexes(h) {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    pub fn contains(&self, h: &InfoHash) -> bool {
+        self.indexes(h).all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    /// Info hashes are SHA-1 digests, uniform enough to take the two hashes
+    /// of the double hashing scheme straight from their bytes.
+    fn indexes(&self, h: &InfoHash) -> impl Iterator<Item = usize> {
+        let b = h.as_bytes();
+        let h1 = BigEndian::read_u64(&b[..8]);
+        let h2 = BigEndian::read_u64(&b[8..16]) | 1;
+        let m = self.bits.len() as u64 * 64;
+        (0..self.k as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_u32::<BigEndian>(self.k)?;
+        w.write_u64::<BigEndian>(self.bits.len() as u64)?;
+        let mut buf = vec![0; self.bits.len() * 8];
+        BigEndian::write_u64_into(&self.bits, &mut buf);
+        w.write_all(&buf)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Bloom> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a saved filter"));
+        }
+        let k = r.read_u32::<BigEndian>()?;
+        let words = r.read_u64::<BigEndian>()? as usize;
+        let mut buf = vec![];
+        r.read_to_end(&mut buf)?;
+        if k == 0 || k > MAX_HASHES || words == 0 || buf.len() != words * 8 {
+            return Err(invalid("truncated filter"));
+        }
+        let mut bits = vec![0; words];
+        BigEndian::read_u64_into(&buf, &mut bits);
+        Ok(Bloom { bits, k })
+    }
+}
+
+/// What to do with an announce, see `Dedupe::claim`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Claim {
+    /// never fetched, it's up to the caller now
+    New,
+    /// already in the store
+    Resolved,
+    /// another announce of it is being fetched
+    InFlight,
+}
+
+impl Claim {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Claim::New => "new",
+            Claim::Resolved => "resolved",
+            Claim::InFlight => "in_flight",
+        }
+    }
+}
+
+pub struct Dedupe {
+    filter: Mutex<Bloom>,
+    store: Arc<dyn TorrentStore>,
+    in_flight: Mutex<HashSet<InfoHash>>,
+    /// where the filter is saved, not saved if `None`
+    path: Option<PathBuf>,
+    /// changed since the last save
+    dirty: AtomicBool,
+}
+
+/// Loads the filter saved at `path`, or builds one from `store` if there is
+/// none or it was sized differently. An empty `path` keeps it in memory only.
+pub fn open(path: &str, capacity: u64, fp_rate: f64, store: Arc<dyn TorrentStore>) -> Result<Dedupe, String> {
+    let path = if path.is_empty() { None } else { Some(PathBuf::from(path)) };
+    let empty = Bloom::new(capacity, fp_rate);
+    let saved = match path {
+        Some(ref p) => match fs::File::open(p) {
+            Ok(mut f) => match Bloom::read_from(&mut io::BufReader::new(&mut f)) {
+                Ok(b) if b.k == empty.k && b.bits.len() == empty.bits.len() => Some(b),
+                Ok(_) => {
+                    warn!(path = %p.display(), "saved filter has another size, rebuilding it");
+                    None
+                }
+                Err(e) => {
+                    warn!(path = %p.display(), error = %e, "couldn't load the saved filter, rebuilding it");
+                    None
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("{}: {}", p.display(), e)),
+        },
+        None => None,
+    };
+    let rebuilt = saved.is_none();
+    let filter = match saved {
+        Some(b) => b,
+        None => {
+            let mut b = empty;
+            let hashes = store.list()?;
+            for h in hashes.iter() {
+                b.insert(h);
+            }
+            info!(torrents = hashes.len(), "built the seen filter from the store");
+            b
+        }
+    };
+    Ok(Dedupe {
+        filter: Mutex::new(filter),
+        store,
+        in_flight: Mutex::new(HashSet::new()),
+        path,
+        dirty: AtomicBool::new(rebuilt),
+    })
+}
+
+impl Dedupe {
+    /// Tells whether `hash` needs fetching. A `New` hash counts as in flight
+    /// until `release`.
+    pub fn claim(&self, hash: &InfoHash) -> Claim {
+        if self.filter.lock().unwrap().contains(hash) && self.store.exists(hash).unwrap_or(false) {
+            return Claim::Resolved;
+        }
+        if !self.in_flight.lock().unwrap().insert(*hash) {
+            return Claim::InFlight;
+        }
+        Claim::New
+    }
+
+    /// Ends the fetch of a claimed `hash`, `resolved` if it is now in the
+    /// store.
+    pub fn release(&self, hash: &InfoHash, resolved: bool) {
+        if resolved {
+            self.filter.lock().unwrap().insert(hash);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        self.in_flight.lock().unwrap().remove(hash);
+    }
+
+    /// Writes the filter to its file if it changed, through a temporary file
+    /// so that a crash leaves the previous one.
+    pub fn save(&self) -> Result<(), String> {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let filter = self.filter.lock().unwrap().clone();
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let write = || -> io::Result<()> {
+            let mut f = io::BufWriter::new(fs::File::create(&tmp)?);
+            filter.write_to(&mut f)?;
+            f.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp, path)
+        };
+        write().map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            self.dirty.store(true, Ordering::Relaxed);
+            format!("{}: {}", path.display(), e)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::store::fs::FsStore;
+    use super::super::testutil::TempDir;
+
+    #[test]
+    fn bloom() {
+        let mut b = Bloom::new(1000, 0.01);
+        let held: Vec<_> = (0..1000).map(|_| InfoHash::random()).collect();
+        for h in held.iter() {
+            b.insert(h);
+        }
+        assert!(held.iter().all(|h| b.contains(h)));
+        let false_positives = (0..10000).filter(|_| b.contains(&InfoHash::random())).count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        let mut buf = vec![];
+        b.write_to(&mut buf).unwrap();
+        assert_eq!(Bloom::read_from(&mut &buf[..]).unwrap(), b);
+        assert!(Bloom::read_from(&mut &buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn claim_and_reload() {
+        let dir = TempDir::new("dedupe");
+        let store: Arc<dyn TorrentStore> = Arc::new(FsStore::open(dir.join("store")).unwrap());
+        let (a, b, c) = (InfoHash::random(), InfoHash::random(), InfoHash::random());
+        store.put(&a, b"d4:name1:ae").unwrap();
+        let path = dir.join("seen.bloom").to_string_lossy().into_owned();
+
+        // rebuilt from the store
+        let d = open(&path, 100, 0.01, store.clone()).unwrap();
+        assert_eq!(d.claim(&a), Claim::Resolved);
+        assert_eq!(d.claim(&b), Claim::New);
+        assert_eq!(d.claim(&b), Claim::InFlight);
+        d.release(&b, false);
+        assert_eq!(d.claim(&b), Claim::New);
+        store.put(&b, b"d4:name1:be").unwrap();
+        d.release(&b, true);
+        assert_eq!(d.claim(&b), Claim::Resolved);
+        d.save().unwrap();
+
+        // loaded from the file, c was stored behind its back
+        store.put(&c, b"d4:name1:ce").unwrap();
+        let d = open(&path, 100, 0.01, store.clone()).unwrap();
+        assert_eq!(d.claim(&b), Claim::Resolved);
+        assert_eq!(d.claim(&c), Claim::New);
+
+        // resized, so rebuilt again
+        let d = open(&path, 1000, 0.01, store).unwrap();
+        assert_eq!(d.claim(&c), Claim::Resolved);
+    }
+}
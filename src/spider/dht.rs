@@ -0,0 +1,709 @@
+extern crate bencode;
+extern crate rand;
+extern crate sha1;
+extern crate socket2;
+extern crate tokio;
+extern crate tokio_stream;
+
+use rand::prelude::*;
+use std::io;
+use std::net;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::field::Empty;
+use tracing::{debug, instrument, warn, Span};
+
+pub mod compact;
+mod error;
+pub mod krpc;
+pub mod ratelimit;
+pub mod routing;
+pub mod token;
+pub mod transaction;
+
+pub use self::error::DhtError;
+use self::krpc::{Message, Query, Response};
+use self::ratelimit::{Limiter, Traffic};
+use self::token::Tokens;
+use self::transaction::Transactions;
+use super::blocklist::Blocklist;
+use super::id::{InfoHash, NodeId};
+use super::metrics::Metrics;
+
+const REFRESH_INTERVAL_SEC: u64 = 60;
+/// discovered nodes waiting for a find_node, newer ones are dropped when full
+const NODE_QUEUE_LEN: usize = 1024;
+/// announces waiting for the consumer, newer ones are dropped when full
+const ANNOUNCE_QUEUE_LEN: usize = 1024;
+/// pause after a failed receive, so that a socket failing for good doesn't
+/// spin
+const RECV_ERROR_PAUSE_MILLIS: u64 = 100;
+
+pub const DEFAULT_ADDR: &str = "0.0.0.0:34254";
+pub const DEFAULT_ADDR6: &str = "[::]:34254";
+pub const DEFAULT_SECRET: &str = "IYHJFR%^&IO";
+
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881"];
+
+#[derive(Clone)]
+pub struct Node {
+    addr: String,
+    id: NodeId,
+}
+
+#[derive(Clone)]
+pub struct Announce {
+    pub peer: net::SocketAddr,
+    pub info_hash: InfoHash,
+}
+
+
+/// Announces received by a running node, see `RustDHT::start`.
+pub type AnnounceStream = ReceiverStream<Announce>;
+
+pub fn rand_bytes(n: i32) -> Vec<u8> {
+    (0..n).map(|_| random::<u8>()).collect()
+}
+
+
+
+pub struct RustDHT {
+    table: Arc<Mutex<routing::Table>>,
+    /// table of the instance serving the other address family, see `start_dual`
+    other_table: Option<Arc<Mutex<routing::Table>>>,
+    v6: bool,
+    local_id: NodeId,
+    conn: net::UdpSocket,
+    max_friends_per_sec: u32,
+    max_replies_per_sec: u32,
+    max_pings_per_sec: u32,
+    /// 0 for no cap
+    max_bytes_per_sec: u64,
+    secret: String,
+    token_rotate: Duration,
+    bootstraps: Vec<String>,
+    metrics: Arc<Metrics>,
+    blocklist: Arc<Blocklist>,
+}
+
+impl RustDHT {
+    pub fn max_friends_per_sec(mut self, n: u32) -> RustDHT {
+        self.max_friends_per_sec = n;
+        self
+    }
+    pub fn max_replies_per_sec(mut self, n: u32) -> RustDHT {
+        self.max_replies_per_sec = n;
+        self
+    }
+    pub fn max_pings_per_sec(mut self, n: u32) -> RustDHT {
+        self.max_pings_per_sec = n;
+        self
+    }
+    /// Caps everything the node sends, 0 for no cap.
+    pub fn max_bytes_per_sec(mut self, n: u64) -> RustDHT {
+        self.max_bytes_per_sec = n;
+        self
+    }
+    pub fn local_id(mut self, id: NodeId) -> RustDHT {
+        self.table = Arc::new(Mutex::new(routing::Table::new(id)));
+        self.local_id = id;
+        self
+    }
+    pub fn secret(mut self, s: String) -> RustDHT {
+        self.secret = s;
+        self
+    }
+    /// How often the random part of the announce tokens changes.
+    pub fn token_rotate_sec(mut self, n: u64) -> RustDHT {
+        self.token_rotate = Duration::from_secs(n.max(1));
+        self
+    }
+    pub fn bootstraps(mut self, addr: Vec<String>) -> RustDHT {
+        self.bootstraps = addr;
+        self
+    }
+    /// Records what the node sees in `m`, which can be shared with other
+    /// nodes and the fetchers.
+    pub fn metrics(mut self, m: Arc<Metrics>) -> RustDHT {
+        self.metrics = m;
+        self
+    }
+    /// Ignores the nodes in the ranges of `b`. Its bans don't apply to nodes:
+    /// the source of a UDP packet can be forged.
+    pub fn blocklist(mut self, b: Arc<Blocklist>) -> RustDHT {
+        self.blocklist = b;
+        self
+    }
+}
+
+/// Binds an IPv4 or IPv6 node depending on `addr`. IPv6 sockets are v6 only,
+/// so that an IPv4 node can listen on the same port.
+pub fn bind(addr: &str) -> Result<RustDHT, DhtError> {
+    let sock_addr = addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+    let socket = if sock_addr.is_ipv6() {
+        let s = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+        s.set_only_v6(true)?;
+        s.bind(&sock_addr.into())?;
+        s.into()
+    } else {
+        net::UdpSocket::bind(sock_addr)?
+    };
+    let local_id = NodeId::random();
+    let mut result = RustDHT {
+        table: Arc::new(Mutex::new(routing::Table::new(local_id))),
+        other_table: None,
+        v6: sock_addr.is_ipv6(),
+        local_id,
+        conn: socket,
+        max_friends_per_sec: ratelimit::DEFAULT_FRIENDS_PER_SEC,
+        max_replies_per_sec: ratelimit::DEFAULT_REPLIES_PER_SEC,
+        max_pings_per_sec: ratelimit::DEFAULT_PINGS_PER_SEC,
+        max_bytes_per_sec: 0,
+        secret: String::from(DEFAULT_SECRET),
+        token_rotate: Duration::from_secs(token::DEFAULT_ROTATE_SEC),
+        bootstraps: vec![],
+        metrics: Arc::new(Metrics::new()),
+        blocklist: Arc::new(Blocklist::default()),
+    };
+    for s in BOOTSTRAP_NODES.iter() {
+        result.bootstraps.push(s.to_string());
+    }
+    Ok(result)
+}
+
+/// Read-only view of a node, usable while it runs.
+#[derive(Clone)]
+pub struct Status {
+    table: Arc<Mutex<routing::Table>>,
+}
+
+impl Status {
+    /// Number of nodes in the routing table.
+    pub fn nodes(&self) -> usize {
+        self.table.lock().unwrap().nodes()
+    }
+
+    /// Mean round-trip time of the nodes in the routing table.
+    pub fn mean_rtt(&self) -> Option<Duration> {
+        self.table.lock().unwrap().mean_rtt()
+    }
+}
+
+impl RustDHT {
+    pub fn status(&self) -> Status {
+        Status { table: self.table.clone() }
+    }
+
+    /// Starts the node on the current tokio runtime and returns its tasks and
+    /// the announces it receives.
+    pub fn start(self) -> Result<(Vec<JoinHandle<()>>, AnnounceStream), DhtError> {
+        let (sender_announce, rx_announce) = mpsc::channel(ANNOUNCE_QUEUE_LEN);
+        Ok((self.start_with(sender_announce)?, ReceiverStream::new(rx_announce)))
+    }
+
+    /// Starts this node together with `other`, which serves the other address
+    /// family (BEP 32). Each node answers `want` with the table of the other
+    /// one and the announces of both are merged into a single stream.
+    pub fn start_dual(mut self, mut other: RustDHT) -> Result<(Vec<JoinHandle<()>>, AnnounceStream), DhtError> {
+        self.other_table = Some(other.table.clone());
+        other.other_table = Some(self.table.clone());
+        let (sender_announce, rx_announce) = mpsc::channel(ANNOUNCE_QUEUE_LEN);
+        let mut h = self.start_with(sender_announce.clone())?;
+        h.append(&mut other.start_with(sender_announce)?);
+        Ok((h, ReceiverStream::new(rx_announce)))
+    }
+
+    /// Starts the node, sending the announces it receives to `sender_announce`.
+    ///
+    /// Receiving, making friends and refreshing the table run as separate
+    /// tasks. They share the socket and only lock the routing table, never
+    /// across an await.
+    pub fn start_with(mut self, sender_announce: mpsc::Sender<Announce>) -> Result<Vec<JoinHandle<()>>, DhtError> {
+        let bootstraps = std::mem::take(&mut self.bootstraps);
+        let shared = Arc::new(self.into_shared()?);
+        let (sender_node, mut rx_node) = mpsc::channel(NODE_QUEUE_LEN);
+
+        let j = sender_node.clone();
+        let handle_join = tokio::spawn(async move {
+            for s in bootstraps {
+                if j.send(Node { addr: s, id: NodeId::random() }).await.is_err() {
+                    warn!("node queue closed before joining");
+                    return;
+                }
+            }
+        });
+
+        let tx_node = sender_node;
+        let tx_an = sender_announce;
+        let tmp = shared.clone();
+        let handle_listen = tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let mut errors = 0u32;
+            loop {
+                match tmp.conn.recv_from(&mut buf).await {
+                    Ok((amt, src)) => {
+                        errors = 0;
+                        tmp.on_message(&buf[..amt], src, &tx_node, &tx_an).await;
+                        continue;
+                    }
+                    // e.g. an ICMP port unreachable for an earlier send on Windows
+                    Err(e) if errors == 0 => debug!(error = %e, "couldn't receive"),
+                    Err(e) => warn!(error = %e, errors, "couldn't receive again"),
+                }
+                errors = errors.saturating_add(1);
+                time::sleep(Duration::from_millis(RECV_ERROR_PAUSE_MILLIS)).await;
+            }
+        });
+
+        let tmp = shared.clone();
+        let handle_mk_friends = tokio::spawn(async move {
+            while let Some(n) = rx_node.recv().await {
+                let q = Query::FindNode {
+                    id: tmp.local_id.neighbour(&n.id),
+                    target: NodeId::random(),
+                    want: vec![],
+                };
+
+                match tmp.resolve(&n.addr).await {
+                    Some(addr) => tmp.query(q, addr).await,
+                    None => warn!(node = %n.addr, "couldn't resolve"),
+                }
+            }
+        });
+
+        let tmp = shared.clone();
+        let handle_refresh = tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(REFRESH_INTERVAL_SEC));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                tmp.refresh_table().await;
+            }
+        });
+
+        let tmp = shared;
+        let handle_expire = tokio::spawn(async move {
+            let mut interval = time::interval(transaction::DEFAULT_TIMEOUT);
+            loop {
+                interval.tick().await;
+                let expired = tmp.transactions.lock().unwrap().expire(Instant::now());
+                tmp.metrics.transactions.with_label_values(&["timeout"]).inc_by(expired.len() as u64);
+            }
+        });
+        Ok(vec![handle_join, handle_listen, handle_mk_friends, handle_refresh, handle_expire])
+    }
+
+    /// The state of the node once running, its socket registered with the
+    /// current tokio runtime.
+    fn into_shared(self) -> Result<Shared, DhtError> {
+        self.conn.set_nonblocking(true)?;
+        Ok(Shared {
+            conn: UdpSocket::from_std(self.conn)?,
+            table: self.table,
+            other_table: self.other_table,
+            v6: self.v6,
+            local_id: self.local_id,
+            tokens: Tokens::new(&self.secret, self.token_rotate),
+            transactions: Mutex::new(Transactions::new(transaction::DEFAULT_TIMEOUT)),
+            limiter: Limiter::new(self.max_friends_per_sec, self.max_replies_per_sec, self.max_pings_per_sec,
+                                  self.max_bytes_per_sec),
+            metrics: self.metrics,
+            blocklist: self.blocklist,
+        })
+    }
+}
+
+/// State of a running node, shared by its tasks.
+struct Shared {
+    conn: UdpSocket,
+    table: Arc<Mutex<routing::Table>>,
+    other_table: Option<Arc<Mutex<routing::Table>>>,
+    v6: bool,
+    local_id: NodeId,
+    tokens: Tokens,
+    /// queries waiting for an answer
+    transactions: Mutex<Transactions>,
+    limiter: Limiter,
+    metrics: Arc<Metrics>,
+    blocklist: Arc<Blocklist>,
+}
+
+impl Shared {
+    /// First address of `addr` in the family of this node.
+    async fn resolve(&self, addr: &str) -> Option<net::SocketAddr> {
+        tokio::net::lookup_host(addr).await.ok().and_then(|mut a| a.find(|a| a.is_ipv6() == self.v6))
+    }
+
+    #[instrument(name = "krpc", level = "debug", skip_all, fields(from = %addr, method = Empty))]
+    async fn on_message(&self, dat: &[u8], addr: net::SocketAddr, tx_node: &mpsc::Sender<Node>, tx_announce: &mpsc::Sender<Announce>) {
+        if self.blocklist.is_listed(addr.ip()) {
+            self.metrics.blocked.with_label_values(&["krpc"]).inc();
+            return;
+        }
+        let msg = match Message::decode(dat) {
+            Ok(msg) => msg,
+            Err(e) => {
+                debug!(error = %e.error, "invalid message");
+                self.metrics.packets.with_label_values(&["invalid"]).inc();
+                let code = e.code();
+                if let Some(t) = e.t {
+                    self.reply(&Message::error(t, code), addr).await;
+                }
+                return;
+            }
+        };
+        match msg.body {
+            krpc::Body::Query(ref q) => {
+                Span::current().record("method", q.method());
+                self.metrics.packets.with_label_values(&[q.method()]).inc();
+                self.table.lock().unwrap().insert(*q.id(), addr);
+                if let Err(e) = self.on_query(&msg, q, addr, tx_announce).await {
+                    debug!(error = %e, "refused");
+                    self.reply(&Message::error(msg.t.clone(), e.code()), addr).await;
+                }
+            }
+            krpc::Body::Response(ref r) => {
+                Span::current().record("method", "response");
+                self.metrics.packets.with_label_values(&["response"]).inc();
+                let pending = match self.finish(&msg.t, addr) {
+                    Some(p) => p,
+                    None => return,
+                };
+                let rtt = pending.sent.elapsed();
+                self.metrics.transactions.with_label_values(&["answered"]).inc();
+                self.metrics.rtt_seconds.observe(rtt.as_secs_f64());
+                {
+                    let mut table = self.table.lock().unwrap();
+                    table.insert(r.id, addr);
+                    table.record_rtt(&r.id, rtt);
+                }
+                // a ping answer only tells that the node is alive
+                if let Query::FindNode { .. } = pending.query {
+                    self.on_nodes(r, tx_node);
+                }
+            }
+            krpc::Body::Error { code, msg: ref reason } => {
+                Span::current().record("method", "error");
+                self.metrics.packets.with_label_values(&["error"]).inc();
+                if let Some(p) = self.finish(&msg.t, addr) {
+                    debug!(code, msg = %reason, query = p.query.method(), "error reply");
+                    self.metrics.transactions.with_label_values(&["error"]).inc();
+                }
+            }
+        }
+    }
+
+    /// Ends the transaction `t` answered by `from`, `None` if the answer is
+    /// to be dropped.
+    fn finish(&self, t: &[u8], from: net::SocketAddr) -> Option<transaction::Pending> {
+        match self.transactions.lock().unwrap().finish(t, from, Instant::now()) {
+            Ok(p) => Some(p),
+            Err(u) => {
+                debug!(reason = u.as_str(), "dropped answer");
+                self.metrics.transactions.with_label_values(&[u.as_str()]).inc();
+                None
+            }
+        }
+    }
+
+    /// Queues the nodes of a find_node answer for a find_node of their own.
+    fn on_nodes(&self, r: &Response, tx_node: &mpsc::Sender<Node>) {
+        let nodes = if self.v6 { &r.nodes6 } else { &r.nodes };
+        if let Some(ref nodes) = *nodes {
+            let nodes = if self.v6 { compact::decode_nodes6(nodes) } else { compact::decode_nodes(nodes) };
+            for (id, addr) in nodes {
+                if self.blocklist.is_listed(addr.ip()) {
+                    continue;
+                }
+                // the crawl budget paces the find_node queries, nodes that
+                // don't fit in the queue are dropped
+                if tx_node.try_send(Node { addr: addr.to_string(), id }).is_ok() {
+                    self.metrics.nodes_queued.inc();
+                }
+            }
+        }
+    }
+
+    /// Pings questionable nodes and looks up random targets in stale buckets.
+    async fn refresh_table(&self) {
+        let to_ping = self.table.lock().unwrap().refresh();
+        for n in to_ping {
+            let q = Query::Ping { id: self.local_id };
+            self.query(q, n.addr).await;
+        }
+        let targets = self.table.lock().unwrap().stale_targets();
+        for target in targets {
+            let closest = self.table.lock().unwrap().closest(&target, routing::K);
+            for n in closest {
+                let q = Query::FindNode { id: self.local_id, target, want: vec![] };
+                self.query(q, n.addr).await;
+            }
+        }
+    }
+
+    /// Fills `nodes` and `nodes6` of `r` with the nodes closest to `target`.
+    /// Without `want`, or with none we know of, only the family of this node
+    /// is returned (BEP 32).
+    fn closest_nodes(&self, r: &mut Response, target: &NodeId, want: &[Vec<u8>]) {
+        let (v4, v6) = match (want.iter().any(|w| w == b"n4"), want.iter().any(|w| w == b"n6")) {
+            (false, false) => (!self.v6, self.v6),
+            wanted => wanted,
+        };
+        for &(wanted, is_v6) in [(v4, false), (v6, true)].iter() {
+            if !wanted {
+                continue;
+            }
+            let table = if is_v6 == self.v6 { Some(&self.table) } else { self.other_table.as_ref() };
+            let closest = match table {
+                Some(t) => t.lock().unwrap().closest(target, routing::K),
+                None => vec![],
+            };
+            let closest = closest.into_iter().map(|e| (e.id, e.addr));
+            if is_v6 {
+                r.nodes6 = Some(compact::encode_nodes6(closest));
+            } else {
+                r.nodes = Some(compact::encode_nodes(closest));
+            }
+        }
+    }
+
+    async fn send(&self, msg: &Message, to: net::SocketAddr) {
+        if let Err(e) = self.conn.send_to(&msg.encode(), to).await {
+            debug!(%to, error = %e, "couldn't send");
+        }
+    }
+
+    /// Waits until the budget of `kind` allows `len` more bytes. Replies are
+    /// not waited for, false means it has to be dropped: the receive loop
+    /// must keep up.
+    async fn acquire(&self, kind: Traffic, len: usize) -> bool {
+        loop {
+            let wait = match self.limiter.check(kind, len) {
+                Ok(()) => return true,
+                Err(wait) => wait,
+            };
+            self.metrics.rate_limited.with_label_values(&[kind.as_str()]).inc();
+            if kind == Traffic::Reply {
+                return false;
+            }
+            time::sleep(wait).await;
+        }
+    }
+
+    /// Sends `q` under a new transaction once the budget allows it, unless
+    /// too many are pending.
+    async fn query(&self, q: Query, to: net::SocketAddr) {
+        let kind = if let Query::Ping { .. } = q { Traffic::Ping } else { Traffic::Crawl };
+        // transaction IDs are always two bytes, the length is known before
+        // the transaction starts, and its RTT doesn't include the wait
+        let mut msg = Message::query(vec![0; 2], q.clone());
+        self.acquire(kind, msg.encode().len()).await;
+        match self.transactions.lock().unwrap().start(q, to, Instant::now()) {
+            Some(t) => msg.t = t,
+            None => {
+                debug!(%to, "too many pending queries");
+                self.metrics.transactions.with_label_values(&["dropped"]).inc();
+                return;
+            }
+        }
+        self.send(&msg, to).await;
+    }
+
+    /// Sends the response or error answering a query, unless the reply
+    /// budget is spent.
+    async fn reply(&self, msg: &Message, to: net::SocketAddr) {
+        if !self.acquire(Traffic::Reply, msg.encode().len()).await {
+            debug!(%to, "reply dropped by the rate limit");
+            return;
+        }
+        let kind = if let krpc::Body::Error { .. } = msg.body { "error" } else { "response" };
+        self.metrics.replies.with_label_values(&[kind]).inc();
+        self.send(msg, to).await;
+    }
+
+    async fn on_query(&self, msg: &Message, q: &Query, from: net::SocketAddr, tx_announce: &mpsc::Sender<Announce>) -> Result<(), DhtError> {
+        let mut r = Response {
+            id: self.local_id.neighbour(q.id()),
+            ..Default::default()
+        };
+        match *q {
+            Query::Ping { .. } => {}
+            Query::FindNode { ref target, ref want, .. } => {
+                self.closest_nodes(&mut r, target, want);
+            }
+            Query::GetPeers { ref info_hash, ref want, .. } => {
+                self.closest_nodes(&mut r, &NodeId::from(*info_hash), want);
+                r.token = Some(self.tokens.generate(from.ip()));
+            }
+            Query::AnnouncePeer { ref info_hash, port, implied_port, ref token, .. } => {
+                if !self.tokens.verify(token, from.ip()) {
+                    self.metrics.announces.with_label_values(&["rejected"]).inc();
+                    return Err(DhtError::BadToken);
+                }
+                let port = if implied_port == Some(true) { from.port() } else { port };
+                // a slow consumer loses announces rather than stalling the node
+                let sent = tx_announce.try_send(Announce {
+                    peer: net::SocketAddr::new(from.ip(), port),
+                    info_hash: *info_hash,
+                });
+                let result = if sent.is_ok() { "accepted" } else { "dropped" };
+                debug!(%info_hash, port, result, "announce");
+                self.metrics.announces.with_label_values(&[result]).inc();
+            }
+            Query::SampleInfohashes { ref target, .. } => {
+                // we don't store peers, so there is nothing to sample
+                self.closest_nodes(&mut r, target, &[]);
+                r.samples = Some(vec![]);
+                r.num = Some(0);
+                r.interval = Some(0);
+            }
+        }
+        self.reply(&Message::response(msg.t.clone(), r), from).await;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::krpc::{Body, ERR_METHOD_UNKNOWN, ERR_PROTOCOL};
+
+    /// A node on a loopback port, not started, and a socket to query it from.
+    fn node() -> (Shared, net::UdpSocket) {
+        let node = bind("127.0.0.1:0").unwrap().into_shared().unwrap();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (node, client)
+    }
+
+    /// Hands `packet` from `client` to `node` and returns the reply.
+    async fn ask(node: &Shared, client: &net::UdpSocket, packet: &[u8]) -> Message {
+        let (tx_node, _rx_node) = mpsc::channel(1);
+        let (tx_announce, _rx_announce) = mpsc::channel(1);
+        node.on_message(packet, client.local_addr().unwrap(), &tx_node, &tx_announce).await;
+        let mut buf = [0; 2048];
+        let (n, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(from, node.conn.local_addr().unwrap());
+        Message::decode(&buf[..n]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replies() {
+        let (node, client) = node();
+        let id = NodeId::random();
+        let reply = ask(&node, &client, &Message::query(b"aa".to_vec(), Query::Ping { id }).encode()).await;
+        assert_eq!(reply.t, b"aa");
+        match reply.body {
+            Body::Response(r) => assert_eq!(r.id, node.local_id.neighbour(&id)),
+            _ => panic!("not a response: {:?}", reply.body),
+        }
+        // the querying node was added to the table
+        let known: net::SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let known_id = NodeId::random();
+        node.table.lock().unwrap().insert(known_id, known);
+        assert_eq!(node.table.lock().unwrap().nodes(), 2);
+
+        let q = Query::FindNode { id, target: known_id, want: vec![] };
+        let reply = ask(&node, &client, &Message::query(b"fn".to_vec(), q).encode()).await;
+        assert_eq!(reply.t, b"fn");
+        let r = match reply.body {
+            Body::Response(r) => r,
+            _ => panic!("not a response: {:?}", reply.body),
+        };
+        let nodes = compact::decode_nodes(&r.nodes.unwrap());
+        assert_eq!(nodes[0], (known_id, known));
+        assert_eq!(nodes.len(), 2);
+        assert!(r.nodes6.is_none());
+    }
+
+    #[tokio::test]
+    async fn error_replies() {
+        let (node, client) = node();
+        let error = |m: Message| match m.body {
+            Body::Error { code, .. } => (m.t, code),
+            _ => panic!("not an error: {:?}", m.body),
+        };
+        // an id of 3 bytes
+        let reply = ask(&node, &client, b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").await;
+        assert_eq!(error(reply), (b"aa".to_vec(), ERR_PROTOCOL));
+        let reply = ask(&node, &client, b"d1:ad2:id20:abcdefghij0123456789e1:q9:find_node1:t2:bb1:y1:qe").await;
+        assert_eq!(error(reply), (b"bb".to_vec(), ERR_PROTOCOL));
+        let reply = ask(&node, &client, b"d1:ade1:q3:foo1:t2:cc1:y1:qe").await;
+        assert_eq!(error(reply), (b"cc".to_vec(), ERR_METHOD_UNKNOWN));
+        let reply = ask(&node, &client, b"d1:ad2:id20:abcdefghij0123456789e1:q3:foo1:t2:dd1:y1:qe").await;
+        assert_eq!(error(reply), (b"dd".to_vec(), ERR_METHOD_UNKNOWN));
+        // none of them made it to the table
+        assert_eq!(node.table.lock().unwrap().nodes(), 0);
+    }
+
+    #[tokio::test]
+    async fn blocklist() {
+        let (mut node, client) = node();
+        let peer = client.local_addr().unwrap().ip();
+        // fetches from the same address got it banned
+        let b = Blocklist::default().ban_threshold(1.0);
+        assert!(b.penalize(peer));
+        assert!(b.is_blocked(peer));
+        node.blocklist = Arc::new(b);
+        let ping = Message::query(b"aa".to_vec(), Query::Ping { id: NodeId::random() }).encode();
+        let reply = ask(&node, &client, &ping).await;
+        assert_eq!(reply.t, b"aa");
+        assert_eq!(node.table.lock().unwrap().nodes(), 1);
+
+        // listed addresses are ignored
+        node.blocklist = Arc::new(Blocklist::new(vec![(peer, peer)]));
+        let (tx_node, _rx_node) = mpsc::channel(1);
+        let (tx_announce, _rx_announce) = mpsc::channel(1);
+        node.on_message(&ping, client.local_addr().unwrap(), &tx_node, &tx_announce).await;
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(client.recv_from(&mut [0; 2048]).is_err());
+    }
+
+    #[tokio::test]
+    async fn want() {
+        let (mut node, _) = node();
+        let (id4, id6) = (NodeId::random(), NodeId::random());
+        let (addr4, addr6) = ("192.0.2.1:6881".parse().unwrap(), "[2001:db8::1]:6881".parse().unwrap());
+        node.table.lock().unwrap().insert(id4, addr4);
+        let mut table6 = routing::Table::new(NodeId::random());
+        table6.insert(id6, addr6);
+        node.other_table = Some(Arc::new(Mutex::new(table6)));
+
+        let closest = |node: &Shared, want: &[&[u8]]| {
+            let mut r = Response::default();
+            let want: Vec<Vec<u8>> = want.iter().map(|w| w.to_vec()).collect();
+            node.closest_nodes(&mut r, &NodeId::random(), &want);
+            (r.nodes.map(|n| compact::decode_nodes(&n)), r.nodes6.map(|n| compact::decode_nodes6(&n)))
+        };
+        let (v4, v6) = (Some(vec![(id4, addr4)]), Some(vec![(id6, addr6)]));
+        assert_eq!(closest(&node, &[]), (v4.clone(), None));
+        assert_eq!(closest(&node, &[b"n4"]), (v4.clone(), None));
+        assert_eq!(closest(&node, &[b"n6"]), (None, v6.clone()));
+        assert_eq!(closest(&node, &[b"n6", b"n4"]), (v4.clone(), v6.clone()));
+        assert_eq!(closest(&node, &[b"n6", b"n5"]), (None, v6.clone()));
+        // unknown families are ignored
+        assert_eq!(closest(&node, &[b"n5"]), (v4.clone(), None));
+
+        // an IPv6 node swaps the defaults, and has nothing to give without
+        // an IPv4 node next to it
+        std::mem::swap(&mut node.table, node.other_table.as_mut().unwrap());
+        node.v6 = true;
+        assert_eq!(closest(&node, &[]), (None, v6.clone()));
+        assert_eq!(closest(&node, &[b"n4", b"n6"]), (v4, v6.clone()));
+        node.other_table = None;
+        assert_eq!(closest(&node, &[b"n4", b"n6"]), (Some(vec![]), v6));
+    }
+}
@@ -0,0 +1,116 @@
+//! Compact node and peer info of BEP 5 (IPv4) and BEP 32 (IPv6).
+//!
+//! A compact peer is the address followed by the port in network byte order,
+//! 6 bytes for IPv4 and 18 bytes for IPv6. A compact node is the 20 bytes
+//! node ID followed by the compact peer, 26 or 38 bytes.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::super::id::{NodeId, ID_LEN};
+
+pub const PEER_LEN: usize = 6;
+pub const PEER6_LEN: usize = 18;
+pub const NODE_LEN: usize = ID_LEN + PEER_LEN;
+pub const NODE6_LEN: usize = ID_LEN + PEER6_LEN;
+
+/// Decodes a compact peer of either family, None if the length is invalid.
+pub fn decode_peer(b: &[u8]) -> Option<SocketAddr> {
+    let ip = match b.len() {
+        PEER_LEN => {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(&b[..4]);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        PEER6_LEN => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&b[..16]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    let n = b.len();
+    let port = u16::from(b[n - 2]) << 8 | u16::from(b[n - 1]);
+    Some(SocketAddr::new(ip, port))
+}
+
+pub fn encode_peer(addr: &SocketAddr) -> Vec<u8> {
+    let mut r = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    r.push((addr.port() >> 8) as u8);
+    r.push(addr.port() as u8);
+    r
+}
+
+/// Decodes the `nodes` key, an empty list if the length is not a multiple of 26.
+pub fn decode_nodes(b: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    decode(b, NODE_LEN)
+}
+
+/// Decodes the `nodes6` key, an empty list if the length is not a multiple of 38.
+pub fn decode_nodes6(b: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    decode(b, NODE6_LEN)
+}
+
+fn decode(b: &[u8], size: usize) -> Vec<(NodeId, SocketAddr)> {
+    if !b.len().is_multiple_of(size) {
+        return vec![];
+    }
+    b.chunks(size)
+        .filter_map(|c| Some((NodeId::from_bytes(&c[..ID_LEN])?, decode_peer(&c[ID_LEN..])?)))
+        .filter(|&(_, addr)| addr.port() != 0)
+        .collect()
+}
+
+/// Encodes the IPv4 nodes for the `nodes` key, IPv6 ones are skipped.
+pub fn encode_nodes<I: IntoIterator<Item = (NodeId, SocketAddr)>>(nodes: I) -> Vec<u8> {
+    encode(nodes, true)
+}
+
+/// Encodes the IPv6 nodes for the `nodes6` key, IPv4 ones are skipped.
+pub fn encode_nodes6<I: IntoIterator<Item = (NodeId, SocketAddr)>>(nodes: I) -> Vec<u8> {
+    encode(nodes, false)
+}
+
+fn encode<I: IntoIterator<Item = (NodeId, SocketAddr)>>(nodes: I, v4: bool) -> Vec<u8> {
+    let mut r = vec![];
+    for (id, addr) in nodes {
+        if addr.is_ipv4() == v4 {
+            r.extend_from_slice(id.as_bytes());
+            r.extend_from_slice(&encode_peer(&addr));
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nodes() {
+        let id = NodeId::from_bytes(b"abcdefghij0123456789").unwrap();
+        let v4: SocketAddr = "91.121.47.12:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+
+        let b = encode_nodes(vec![(id, v4), (id, v6)]);
+        assert_eq!(&b[ID_LEN..], b"\x5b\x79\x2f\x0c\x1a\xe1");
+        assert_eq!(decode_nodes(&b), vec![(id, v4)]);
+
+        let b = encode_nodes6(vec![(id, v4), (id, v6)]);
+        assert_eq!(b.len(), NODE6_LEN);
+        assert_eq!(decode_nodes6(&b), vec![(id, v6)]);
+
+        assert!(decode_nodes(&b[1..]).is_empty());
+    }
+
+    #[test]
+    fn peers() {
+        let v4: SocketAddr = "10.0.0.1:51413".parse().unwrap();
+        assert_eq!(decode_peer(b"\x0a\x00\x00\x01\xc8\xd5"), Some(v4));
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        assert_eq!(decode_peer(&encode_peer(&v6)), Some(v6));
+        assert_eq!(decode_peer(b"short"), None);
+    }
+}
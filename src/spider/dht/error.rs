@@ -0,0 +1,61 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use super::krpc::{ERR_METHOD_UNKNOWN, ERR_PROTOCOL, ERR_SERVER};
+
+/// Why the node failed, or why it refused a message.
+#[derive(Debug)]
+pub enum DhtError {
+    /// binding or using the socket failed
+    Io(io::Error),
+    /// the packet is not bencoded
+    Bencode(String),
+    /// the packet is bencoded but not a KRPC message, e.g. `t` is missing
+    Malformed(&'static str),
+    /// a query argument is missing or has the wrong type or length
+    InvalidArgument(String),
+    /// a query we don't know, possibly from an extension we don't speak
+    UnknownMethod(String),
+    /// an announce_peer whose token we didn't give, or gave to another IP
+    BadToken,
+}
+
+impl DhtError {
+    /// KRPC error code to reply with (BEP 5).
+    pub fn code(&self) -> i64 {
+        match *self {
+            DhtError::Io(_) => ERR_SERVER,
+            DhtError::UnknownMethod(_) => ERR_METHOD_UNKNOWN,
+            _ => ERR_PROTOCOL,
+        }
+    }
+}
+
+impl fmt::Display for DhtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DhtError::Io(ref e) => e.fmt(f),
+            DhtError::Bencode(ref e) => write!(f, "not bencoded: {}", e),
+            DhtError::Malformed(reason) => f.write_str(reason),
+            DhtError::InvalidArgument(ref key) => write!(f, "invalid {}", key),
+            DhtError::UnknownMethod(ref q) => write!(f, "unknown method {}", q),
+            DhtError::BadToken => f.write_str("bad token"),
+        }
+    }
+}
+
+impl Error for DhtError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            DhtError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DhtError {
+    fn from(e: io::Error) -> DhtError {
+        DhtError::Io(e)
+    }
+}
@@ -0,0 +1,500 @@
+//! KRPC messages of the mainline DHT (BEP 5) with the extensions we speak:
+//! `want`/`nodes6` (BEP 32), `ip` (BEP 42), `ro` (BEP 43) and
+//! `sample_infohashes` (BEP 51).
+//!
+//! Keys that are not modelled here are dropped when decoding.
+
+use std::collections::BTreeMap;
+
+use super::bencode::{self, Bencode, DictMap, FromBencode, ToBencode};
+use super::bencode::util::ByteString;
+use super::super::id::{InfoHash, NodeId, ID_LEN};
+use super::super::wire::bencoded_len;
+use super::DhtError;
+
+// KRPC error codes, see BEP 5
+pub const ERR_GENERIC: i64 = 201;
+pub const ERR_SERVER: i64 = 202;
+pub const ERR_PROTOCOL: i64 = 203;
+pub const ERR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// transaction ID, echoed back in the response
+    pub t: Vec<u8>,
+    /// client version
+    pub v: Option<Vec<u8>>,
+    /// compact address of the receiver as seen by the sender
+    pub ip: Option<Vec<u8>>,
+    /// set by read-only nodes which must not be queried
+    pub ro: bool,
+    pub body: Body,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error { code: i64, msg: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping {
+        id: NodeId,
+    },
+    FindNode {
+        id: NodeId,
+        target: NodeId,
+        want: Vec<Vec<u8>>,
+    },
+    GetPeers {
+        id: NodeId,
+        info_hash: InfoHash,
+        want: Vec<Vec<u8>>,
+    },
+    AnnouncePeer {
+        id: NodeId,
+        info_hash: InfoHash,
+        port: u16,
+        implied_port: Option<bool>,
+        token: Vec<u8>,
+    },
+    SampleInfohashes {
+        id: NodeId,
+        target: NodeId,
+    },
+}
+
+/// Responses don't name the query they answer, so every known key is optional
+/// except the ID of the responding node.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Option<Vec<u8>>,
+    pub nodes6: Option<Vec<u8>>,
+    pub values: Option<Vec<Vec<u8>>>,
+    pub token: Option<Vec<u8>>,
+    pub samples: Option<Vec<u8>>,
+    pub interval: Option<i64>,
+    pub num: Option<i64>,
+}
+
+/// A message that could not be decoded.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// Transaction to answer with an error reply, set only when the
+    /// message was a query.
+    pub t: Option<Vec<u8>>,
+    pub error: DhtError,
+}
+
+impl DecodeError {
+    pub fn code(&self) -> i64 {
+        self.error.code()
+    }
+}
+
+impl Query {
+    pub fn id(&self) -> &NodeId {
+        match *self {
+            Query::Ping { ref id } => id,
+            Query::FindNode { ref id, .. } => id,
+            Query::GetPeers { ref id, .. } => id,
+            Query::AnnouncePeer { ref id, .. } => id,
+            Query::SampleInfohashes { ref id, .. } => id,
+        }
+    }
+
+    pub fn method(&self) -> &'static str {
+        match *self {
+            Query::Ping { .. } => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::SampleInfohashes { .. } => "sample_infohashes",
+        }
+    }
+}
+
+impl Message {
+    pub fn query(t: Vec<u8>, q: Query) -> Message {
+        Message { t, v: None, ip: None, ro: false, body: Body::Query(q) }
+    }
+
+    pub fn response(t: Vec<u8>, r: Response) -> Message {
+        Message { t, v: None, ip: None, ro: false, body: Body::Response(r) }
+    }
+
+    /// An error reply with the standard message of `code`.
+    pub fn error(t: Vec<u8>, code: i64) -> Message {
+        let msg = match code {
+            ERR_SERVER => "Server Error",
+            ERR_PROTOCOL => "Protocol Error",
+            ERR_METHOD_UNKNOWN => "Method Unknown",
+            ERR_GENERIC => "Generic Error",
+            _ => "Error",
+        };
+        Message { t, v: None, ip: None, ro: false, body: Body::Error { code, msg: msg.to_string() } }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_bencode().to_bytes().unwrap_or_default()
+    }
+
+    pub fn decode(dat: &[u8]) -> Result<Message, DecodeError> {
+        // the bencode crate recurses once per list or dictionary
+        if bencoded_len(dat) != Some(dat.len()) {
+            return Err(DecodeError { t: None, error: DhtError::Bencode("invalid or nested too deeply".to_string()) });
+        }
+        let ben = bencode::from_buffer(dat).map_err(|e| DecodeError {
+            t: None,
+            error: DhtError::Bencode(e.msg),
+        })?;
+        Message::from_bencode(&ben)
+    }
+}
+
+impl ToBencode for Message {
+    fn to_bencode(&self) -> Bencode {
+        let mut m = BTreeMap::new();
+        put_bytes(&mut m, "t", &self.t);
+        if let Some(ref v) = self.v {
+            put_bytes(&mut m, "v", v);
+        }
+        if let Some(ref ip) = self.ip {
+            put_bytes(&mut m, "ip", ip);
+        }
+        if self.ro {
+            m.insert(ByteString::from_str("ro"), Bencode::Number(1));
+        }
+        match self.body {
+            Body::Query(ref q) => {
+                put_bytes(&mut m, "y", b"q");
+                put_bytes(&mut m, "q", q.method().as_bytes());
+                m.insert(ByteString::from_str("a"), q.to_bencode());
+            }
+            Body::Response(ref r) => {
+                put_bytes(&mut m, "y", b"r");
+                m.insert(ByteString::from_str("r"), r.to_bencode());
+            }
+            Body::Error { code, ref msg } => {
+                put_bytes(&mut m, "y", b"e");
+                m.insert(ByteString::from_str("e"), Bencode::List(vec![
+                    Bencode::Number(code),
+                    Bencode::ByteString(msg.as_bytes().to_vec()),
+                ]));
+            }
+        }
+        Bencode::Dict(m)
+    }
+}
+
+impl ToBencode for Query {
+    fn to_bencode(&self) -> Bencode {
+        let mut a = BTreeMap::new();
+        put_bytes(&mut a, "id", self.id().as_bytes());
+        match *self {
+            Query::Ping { .. } => {}
+            Query::FindNode { ref target, ref want, .. } => {
+                put_bytes(&mut a, "target", target.as_bytes());
+                put_want(&mut a, want);
+            }
+            Query::GetPeers { ref info_hash, ref want, .. } => {
+                put_bytes(&mut a, "info_hash", info_hash.as_bytes());
+                put_want(&mut a, want);
+            }
+            Query::AnnouncePeer { ref info_hash, port, implied_port, ref token, .. } => {
+                put_bytes(&mut a, "info_hash", info_hash.as_bytes());
+                a.insert(ByteString::from_str("port"), Bencode::Number(i64::from(port)));
+                if let Some(implied) = implied_port {
+                    a.insert(ByteString::from_str("implied_port"), Bencode::Number(implied as i64));
+                }
+                put_bytes(&mut a, "token", token);
+            }
+            Query::SampleInfohashes { ref target, .. } => {
+                put_bytes(&mut a, "target", target.as_bytes());
+            }
+        }
+        Bencode::Dict(a)
+    }
+}
+
+impl ToBencode for Response {
+    fn to_bencode(&self) -> Bencode {
+        let mut r = BTreeMap::new();
+        put_bytes(&mut r, "id", self.id.as_bytes());
+        for (k, v) in [
+            ("nodes", &self.nodes),
+            ("nodes6", &self.nodes6),
+            ("token", &self.token),
+            ("samples", &self.samples),
+        ].iter() {
+            if let Some(v) = v {
+                put_bytes(&mut r, k, v);
+            }
+        }
+        if let Some(ref values) = self.values {
+            let list = values.iter().map(|v| Bencode::ByteString(v.clone())).collect();
+            r.insert(ByteString::from_str("values"), Bencode::List(list));
+        }
+        if let Some(n) = self.interval {
+            r.insert(ByteString::from_str("interval"), Bencode::Number(n));
+        }
+        if let Some(n) = self.num {
+            r.insert(ByteString::from_str("num"), Bencode::Number(n));
+        }
+        Bencode::Dict(r)
+    }
+}
+
+impl FromBencode for Message {
+    type Err = DecodeError;
+
+    fn from_bencode(ben: &Bencode) -> Result<Message, DecodeError> {
+        let m = match *ben {
+            Bencode::Dict(ref m) => m,
+            _ => return Err(invalid(None, "not a dict")),
+        };
+        let t = get_bytes(m, "t").ok_or_else(|| invalid(None, "t not found"))?;
+        let y = get_bytes(m, "y").ok_or_else(|| invalid(None, "y not found"))?;
+        let body = match y.as_slice() {
+            b"q" => {
+                let fail = |reason: &'static str| invalid(Some(t.clone()), reason);
+                let q = get_bytes(m, "q").ok_or_else(|| fail("q not found"))?;
+                let a = match m.get(&ByteString::from_str("a")) {
+                    Some(Bencode::Dict(a)) => a,
+                    _ => return Err(fail("a not found")),
+                };
+                Body::Query(decode_query(&q, a).map_err(|error| DecodeError { t: Some(t.clone()), error })?)
+            }
+            b"r" => {
+                let r = match m.get(&ByteString::from_str("r")) {
+                    Some(Bencode::Dict(r)) => r,
+                    _ => return Err(invalid(None, "r not found")),
+                };
+                Body::Response(decode_response(r).ok_or_else(|| invalid(None, "invalid response"))?)
+            }
+            b"e" => match m.get(&ByteString::from_str("e")) {
+                Some(Bencode::List(e)) if e.len() >= 2 => match (&e[0], &e[1]) {
+                    (Bencode::Number(code), Bencode::ByteString(msg)) => Body::Error {
+                        code: *code,
+                        msg: String::from_utf8_lossy(msg).into_owned(),
+                    },
+                    _ => return Err(invalid(None, "invalid error")),
+                },
+                _ => return Err(invalid(None, "e not found")),
+            },
+            _ => return Err(invalid(None, "unknown message type")),
+        };
+        Ok(Message {
+            t,
+            v: get_bytes(m, "v"),
+            ip: get_bytes(m, "ip"),
+            ro: get_int(m, "ro") == Some(1),
+            body,
+        })
+    }
+}
+
+/// The method is checked before the arguments, so that an unknown one gets
+/// 204 whatever it was sent with.
+fn decode_query(q: &[u8], a: &DictMap) -> Result<Query, DhtError> {
+    let id = || get_id(a, "id");
+    Ok(match q {
+        b"ping" => Query::Ping { id: id()? },
+        b"find_node" => Query::FindNode { id: id()?, target: get_id(a, "target")?, want: get_want(a) },
+        b"get_peers" => Query::GetPeers { id: id()?, info_hash: get_id(a, "info_hash")?, want: get_want(a) },
+        b"announce_peer" => {
+            let port = match get_int(a, "port") {
+                Some(p) if p > 0 && p <= 65535 => p as u16,
+                _ => return Err(DhtError::InvalidArgument("port".to_string())),
+            };
+            Query::AnnouncePeer {
+                id: id()?,
+                info_hash: get_id(a, "info_hash")?,
+                port,
+                implied_port: get_int(a, "implied_port").map(|v| v != 0),
+                token: get_bytes(a, "token").ok_or_else(|| DhtError::InvalidArgument("token".to_string()))?,
+            }
+        }
+        b"sample_infohashes" => Query::SampleInfohashes { id: id()?, target: get_id(a, "target")? },
+        _ => return Err(DhtError::UnknownMethod(String::from_utf8_lossy(q).into_owned())),
+    })
+}
+
+fn decode_response(r: &DictMap) -> Option<Response> {
+    let values = match r.get(&ByteString::from_str("values")) {
+        Some(Bencode::List(l)) => Some(l.iter().filter_map(|v| match v {
+            Bencode::ByteString(v) => Some(v.clone()),
+            _ => None,
+        }).collect()),
+        _ => None,
+    };
+    Some(Response {
+        id: get_id(r, "id").ok()?,
+        nodes: get_bytes(r, "nodes"),
+        nodes6: get_bytes(r, "nodes6"),
+        values,
+        token: get_bytes(r, "token"),
+        samples: get_bytes(r, "samples"),
+        interval: get_int(r, "interval"),
+        num: get_int(r, "num"),
+    })
+}
+
+fn invalid(t: Option<Vec<u8>>, reason: &'static str) -> DecodeError {
+    DecodeError { t, error: DhtError::Malformed(reason) }
+}
+
+fn get_bytes(m: &DictMap, key: &str) -> Option<Vec<u8>> {
+    match m.get(&ByteString::from_str(key)) {
+        Some(Bencode::ByteString(v)) => Some(v.clone()),
+        _ => None,
+    }
+}
+
+fn get_int(m: &DictMap, key: &str) -> Option<i64> {
+    match m.get(&ByteString::from_str(key)) {
+        Some(Bencode::Number(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+fn get_id<T: From<[u8; ID_LEN]>>(m: &DictMap, key: &str) -> Result<T, DhtError> {
+    match m.get(&ByteString::from_str(key)) {
+        Some(Bencode::ByteString(v)) if v.len() == ID_LEN => {
+            let mut id = [0; ID_LEN];
+            id.copy_from_slice(v);
+            Ok(T::from(id))
+        }
+        _ => Err(DhtError::InvalidArgument(key.to_string())),
+    }
+}
+
+fn get_want(m: &DictMap) -> Vec<Vec<u8>> {
+    match m.get(&ByteString::from_str("want")) {
+        Some(Bencode::List(l)) => l.iter().filter_map(|v| match v {
+            Bencode::ByteString(v) => Some(v.clone()),
+            _ => None,
+        }).collect(),
+        _ => vec![],
+    }
+}
+
+fn put_bytes(m: &mut DictMap, key: &str, v: &[u8]) {
+    m.insert(ByteString::from_str(key), Bencode::ByteString(v.to_vec()));
+}
+
+fn put_want(m: &mut DictMap, want: &[Vec<u8>]) {
+    if !want.is_empty() {
+        let list = want.iter().map(|w| Bencode::ByteString(w.clone())).collect();
+        m.insert(ByteString::from_str("want"), Bencode::List(list));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // messages in the shape sent by libtorrent ("LT") and uTorrent ("UT"),
+    // with binary transaction IDs, versions and compact addresses
+    const PING_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    const GET_PEERS_QUERY: &[u8] = b"d1:ad2:id20:2\xf5NisQ\xffJ\xec)\xcd\xba\xab\xf2\xfb\xe3F|\xc2g9:info_hash20:\xd1\xc5gj\xe7\xac\x98\xe8\xb1\x9fcVY\x05\x10^<L7\xa2e1:q9:get_peers1:t4:\x8a\x1b\x00\x021:v4:LT\x01\x021:y1:qe";
+    const FIND_NODE_QUERY: &[u8] = b"d1:ad2:id20:2\xf5NisQ\xffJ\xec)\xcd\xba\xab\xf2\xfb\xe3F|\xc2g6:target20:\xd1\xc5gj\xe7\xac\x98\xe8\xb1\x9fcVY\x05\x10^<L7\xa24:wantl2:n42:n6ee1:q9:find_node1:t2:\xfe\x001:v4:UT\xaeX1:y1:qe";
+    const READ_ONLY_PING: &[u8] = b"d1:ad2:id20:2\xf5NisQ\xffJ\xec)\xcd\xba\xab\xf2\xfb\xe3F|\xc2ge1:q4:ping2:roi1e1:t4:pn\x00\x011:y1:qe";
+    const ANNOUNCE_PEER_QUERY: &[u8] = b"d1:ad2:id20:2\xf5NisQ\xffJ\xec)\xcd\xba\xab\xf2\xfb\xe3F|\xc2g12:implied_porti1e9:info_hash20:\xd1\xc5gj\xe7\xac\x98\xe8\xb1\x9fcVY\x05\x10^<L7\xa24:porti6881e5:token6:T\xb4\xa5\xa2\xe3\xf1e1:q13:announce_peer1:t2:\x00\x071:v4:LT\x01\x021:y1:qe";
+    const GET_PEERS_RESPONSE: &[u8] = b"d2:ip6:\xcb\x00q\x07\x85\x9e1:rd2:id20:2\xf5NisQ\xffJ\xec)\xcd\xba\xab\xf2\xfb\xe3F|\xc2g5:nodes52:\xc3\xa5\xfeD\xaeJk\x0cJ\x0e^\x1aI\xb4\xfc\x8f\xb2\xa6O\xe1[y/\x0c\x1a\xe1\x0f\x9c:]+^\x7f\x10(<\xe3\xa9\x8b\x06\xc5\xb3\x1b\xd1\xe1\xf2\xbc\xa5\x0c\xc9\xc8\xd55:token4:\x1d\x0eH\xa9e1:t4:\x8a\x1b\x00\x021:v4:UT\xaeX1:y1:re";
+    const VALUES_RESPONSE: &[u8] = b"d1:rd2:id20:2\xf5NisQ\xffJ\xec)\xcd\xba\xab\xf2\xfb\xe3F|\xc2g5:token4:\xaa\xbb\xcc\xdd6:valuesl6:[y/\x0c\x1a\xe16:\x0a\x00\x00\x01\xc8\xd5ee1:t2:gp1:y1:re";
+    const SAMPLES_RESPONSE: &[u8] = b"d1:rd2:id20:2\xf5NisQ\xffJ\xec)\xcd\xba\xab\xf2\xfb\xe3F|\xc2g8:intervali21600e5:nodes52:\xc3\xa5\xfeD\xaeJk\x0cJ\x0e^\x1aI\xb4\xfc\x8f\xb2\xa6O\xe1[y/\x0c\x1a\xe1\x0f\x9c:]+^\x7f\x10(<\xe3\xa9\x8b\x06\xc5\xb3\x1b\xd1\xe1\xf2\xbc\xa5\x0c\xc9\xc8\xd53:numi4213e7:samples40:\xd1\xc5gj\xe7\xac\x98\xe8\xb1\x9fcVY\x05\x10^<L7\xa22\xf5NisQ\xffJ\xec)\xcd\xba\xab\xf2\xfb\xe3F|\xc2ge1:t2:si1:v4:LT\x02\x001:y1:re";
+    const ERROR_REPLY: &[u8] = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+
+    #[test]
+    fn round_trip() {
+        for packet in [
+            PING_QUERY,
+            GET_PEERS_QUERY,
+            FIND_NODE_QUERY,
+            READ_ONLY_PING,
+            ANNOUNCE_PEER_QUERY,
+            GET_PEERS_RESPONSE,
+            VALUES_RESPONSE,
+            SAMPLES_RESPONSE,
+            ERROR_REPLY,
+        ].iter() {
+            let msg = Message::decode(packet).unwrap();
+            assert_eq!(msg.encode(), packet.to_vec(), "{:?}", msg);
+        }
+    }
+
+    #[test]
+    fn decode_fields() {
+        let msg = Message::decode(GET_PEERS_QUERY).unwrap();
+        assert_eq!(msg.t, b"\x8a\x1b\x00\x02".to_vec());
+        assert_eq!(msg.v, Some(b"LT\x01\x02".to_vec()));
+        assert!(!msg.ro);
+        match msg.body {
+            Body::Query(Query::GetPeers { ref info_hash, .. }) => assert_eq!(info_hash.as_bytes()[0], 0xd1),
+            ref b => panic!("unexpected body {:?}", b),
+        }
+
+        assert!(Message::decode(READ_ONLY_PING).unwrap().ro);
+
+        let msg = Message::decode(GET_PEERS_RESPONSE).unwrap();
+        assert_eq!(msg.ip, Some(b"\xcb\x00q\x07\x85\x9e".to_vec()));
+        match msg.body {
+            Body::Response(ref r) => {
+                assert_eq!(r.nodes.as_ref().map(|n| n.len()), Some(52));
+                assert_eq!(r.token, Some(b"\x1d\x0eH\xa9".to_vec()));
+            }
+            ref b => panic!("unexpected body {:?}", b),
+        }
+
+        match Message::decode(ANNOUNCE_PEER_QUERY).unwrap().body {
+            Body::Query(Query::AnnouncePeer { port, implied_port, .. }) => {
+                assert_eq!(port, 6881);
+                assert_eq!(implied_port, Some(true));
+            }
+            ref b => panic!("unexpected body {:?}", b),
+        }
+    }
+
+    #[test]
+    fn encode_reply() {
+        let r = Response {
+            id: NodeId::from_bytes(b"mnopqrstuvwxyz123456").unwrap(),
+            ..Default::default()
+        };
+        // the example pong of BEP 5
+        assert_eq!(Message::response(b"aa".to_vec(), r).encode(),
+                   b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re".to_vec());
+        assert_eq!(Message::error(b"aa".to_vec(), ERR_METHOD_UNKNOWN).encode(),
+                   b"d1:eli204e14:Method Unknowne1:t2:aa1:y1:ee".to_vec());
+    }
+
+    #[test]
+    fn decode_errors() {
+        let e = Message::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q3:foo1:t2:aa1:y1:qe").unwrap_err();
+        assert_eq!(e.code(), ERR_METHOD_UNKNOWN);
+        assert_eq!(e.t, Some(b"aa".to_vec()));
+        assert!(matches!(e.error, DhtError::UnknownMethod(ref q) if q == "foo"));
+        // whatever the arguments
+        let e = Message::decode(b"d1:ade1:q3:foo1:t2:aa1:y1:qe").unwrap_err();
+        assert_eq!(e.code(), ERR_METHOD_UNKNOWN);
+
+        let e = Message::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").unwrap_err();
+        assert_eq!(e.code(), ERR_PROTOCOL);
+        assert_eq!(e.t, Some(b"aa".to_vec()));
+        assert!(matches!(e.error, DhtError::InvalidArgument(ref key) if key == "id"));
+
+        // malformed replies are never answered
+        let e = Message::decode(b"d1:rd2:id3:abce1:t2:aa1:y1:re").unwrap_err();
+        assert_eq!(e.t, None);
+        let e = Message::decode(b"garbage").unwrap_err();
+        assert!(e.t.is_none());
+        assert!(matches!(e.error, DhtError::Bencode(_)));
+        // a whole packet of nesting used to overflow the stack
+        let e = Message::decode(&[b'l'; 65507]).unwrap_err();
+        assert!(matches!(e.error, DhtError::Bencode(_)));
+    }
+}
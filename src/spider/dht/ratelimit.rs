@@ -0,0 +1,174 @@
+//! Budgets of the packets a node sends: find_node queries of the crawl,
+//! replies and pings each have their own rate, and all of them together may
+//! be capped in bytes per second.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_FRIENDS_PER_SEC: u32 = 50;
+pub const DEFAULT_REPLIES_PER_SEC: u32 = 500;
+pub const DEFAULT_PINGS_PER_SEC: u32 = 20;
+/// smallest burst of the byte budget, so that a low cap still lets a whole
+/// packet through
+const MIN_BYTES_BURST: f64 = 2048.0;
+
+/// Refills `rate` tokens per second, holding at most `burst`.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full.
+    pub fn new(rate: f64, burst: f64, now: Instant) -> TokenBucket {
+        TokenBucket { rate, burst, tokens: burst, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    /// Time until `n` tokens are available, zero if they are now.
+    pub fn wait(&mut self, n: f64, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= n {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((n - self.tokens) / self.rate)
+    }
+
+    pub fn take(&mut self, n: f64) {
+        self.tokens -= n;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traffic {
+    /// find_node queries discovering nodes
+    Crawl,
+    /// responses and errors answering queries
+    Reply,
+    /// pings of questionable nodes
+    Ping,
+}
+
+impl Traffic {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Traffic::Crawl => "crawl",
+            Traffic::Reply => "reply",
+            Traffic::Ping => "ping",
+        }
+    }
+}
+
+struct Buckets {
+    crawl: TokenBucket,
+    reply: TokenBucket,
+    ping: TokenBucket,
+    /// `None` if unlimited
+    bytes: Option<TokenBucket>,
+}
+
+pub struct Limiter {
+    buckets: Mutex<Buckets>,
+}
+
+/// A bucket allowing a burst of one second.
+fn per_sec(rate: u32, now: Instant) -> TokenBucket {
+    let rate = f64::from(rate.max(1));
+    TokenBucket::new(rate, rate, now)
+}
+
+impl Limiter {
+    /// `bytes_per_sec` of 0 doesn't cap the bytes.
+    pub fn new(crawl_per_sec: u32, replies_per_sec: u32, pings_per_sec: u32, bytes_per_sec: u64) -> Limiter {
+        let now = Instant::now();
+        let bytes = if bytes_per_sec == 0 {
+            None
+        } else {
+            let rate = bytes_per_sec as f64;
+            Some(TokenBucket::new(rate, rate.max(MIN_BYTES_BURST), now))
+        };
+        Limiter {
+            buckets: Mutex::new(Buckets {
+                crawl: per_sec(crawl_per_sec, now),
+                reply: per_sec(replies_per_sec, now),
+                ping: per_sec(pings_per_sec, now),
+                bytes,
+            }),
+        }
+    }
+
+    /// Takes a packet of `len` bytes from the budgets of `kind` if both
+    /// allow it, or returns how long to wait before trying again.
+    pub fn check(&self, kind: Traffic, len: usize) -> Result<(), Duration> {
+        self.check_at(kind, len, Instant::now())
+    }
+
+    fn check_at(&self, kind: Traffic, len: usize, now: Instant) -> Result<(), Duration> {
+        let mut b = self.buckets.lock().unwrap();
+        let Buckets { ref mut crawl, ref mut reply, ref mut ping, ref mut bytes } = *b;
+        let packets = match kind {
+            Traffic::Crawl => crawl,
+            Traffic::Reply => reply,
+            Traffic::Ping => ping,
+        };
+        let len = len as f64;
+        let wait = packets.wait(1.0, now).max(bytes.as_mut().map_or(Duration::ZERO, |b| b.wait(len, now)));
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        packets.take(1.0);
+        if let Some(b) = bytes.as_mut() {
+            b.take(len);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let now = Instant::now();
+        let mut b = TokenBucket::new(10.0, 5.0, now);
+        for _ in 0..5 {
+            assert_eq!(b.wait(1.0, now), Duration::ZERO);
+            b.take(1.0);
+        }
+        assert_eq!(b.wait(1.0, now), Duration::from_millis(100));
+        assert_eq!(b.wait(1.0, now + Duration::from_millis(100)), Duration::ZERO);
+        // never more than the burst
+        assert_eq!(b.wait(5.0, now + Duration::from_secs(60)), Duration::ZERO);
+        assert!(b.wait(6.0, now + Duration::from_secs(60)) > Duration::ZERO);
+    }
+
+    #[test]
+    fn budgets() {
+        let l = Limiter::new(2, 1, 1, 0);
+        let now = l.buckets.lock().unwrap().crawl.updated;
+        assert!(l.check_at(Traffic::Crawl, 100, now).is_ok());
+        assert!(l.check_at(Traffic::Crawl, 100, now).is_ok());
+        assert_eq!(l.check_at(Traffic::Crawl, 100, now), Err(Duration::from_millis(500)));
+        // the other budgets are untouched
+        assert!(l.check_at(Traffic::Reply, 100, now).is_ok());
+        assert!(l.check_at(Traffic::Reply, 100, now).is_err());
+        assert!(l.check_at(Traffic::Ping, 100, now).is_ok());
+
+        let l = Limiter::new(1000, 1000, 1000, 4000);
+        let now = l.buckets.lock().unwrap().crawl.updated;
+        assert!(l.check_at(Traffic::Reply, 3000, now).is_ok());
+        // over the byte cap whatever the kind, and nothing is taken
+        assert_eq!(l.check_at(Traffic::Crawl, 2000, now), Err(Duration::from_millis(250)));
+        assert!(l.check_at(Traffic::Ping, 1000, now).is_ok());
+        assert!(l.check_at(Traffic::Ping, 1, now).is_err());
+    }
+}
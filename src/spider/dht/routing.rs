@@ -0,0 +1,445 @@
+//! Kademlia routing table as described in BEP 5.
+//!
+//! Buckets are indexed by the length of the prefix a node ID shares with the
+//! local ID. The last bucket holds every ID sharing a longer prefix, so it is
+//! the only one covering the local ID and the only one that gets split.
+
+use std::cmp;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::super::id::{NodeId, ID_LEN};
+
+/// Maximum number of nodes per bucket.
+pub const K: usize = 8;
+const ID_BITS: usize = ID_LEN * 8;
+/// A node is good if we heard from it within this period.
+const GOOD_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// Unanswered refresh pings after which a node is considered bad.
+const MAX_FAILED_PINGS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    Good,
+    Questionable,
+    Bad,
+}
+
+#[derive(Clone)]
+pub struct Entry {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    last_seen: Instant,
+    pinged: bool,
+    failed: u32,
+    /// smoothed round-trip time of our queries, `None` until one is answered
+    rtt: Option<Duration>,
+}
+
+impl Entry {
+    fn new(id: NodeId, addr: SocketAddr, now: Instant) -> Entry {
+        Entry { id, addr, last_seen: now, pinged: false, failed: 0, rtt: None }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn state(&self, now: Instant) -> NodeState {
+        if self.failed >= MAX_FAILED_PINGS {
+            NodeState::Bad
+        } else if now.duration_since(self.last_seen) < GOOD_TIMEOUT {
+            NodeState::Good
+        } else {
+            NodeState::Questionable
+        }
+    }
+}
+
+struct Bucket {
+    nodes: Vec<Entry>,
+    /// Candidates taking the place of nodes going bad, most recent last.
+    replacements: Vec<Entry>,
+    last_changed: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Bucket {
+        Bucket { nodes: Vec::with_capacity(K), replacements: vec![], last_changed: now }
+    }
+
+    fn add_replacement(&mut self, e: Entry) {
+        self.replacements.retain(|r| r.id != e.id);
+        if self.replacements.len() >= K {
+            self.replacements.remove(0);
+        }
+        self.replacements.push(e);
+    }
+}
+
+pub struct Table {
+    local: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl Table {
+    pub fn new(local: NodeId) -> Table {
+        Table { local, buckets: vec![Bucket::new(Instant::now())] }
+    }
+
+    /// Number of nodes in the buckets, replacements not included.
+    pub fn nodes(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    /// Mean round-trip time of the nodes that answered a query of ours.
+    pub fn mean_rtt(&self) -> Option<Duration> {
+        let rtts: Vec<Duration> = self.buckets.iter().flat_map(|b| b.nodes.iter()).filter_map(|e| e.rtt()).collect();
+        if rtts.is_empty() {
+            return None;
+        }
+        Some(rtts.iter().sum::<Duration>() / rtts.len() as u32)
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        cmp::min(self.local.common_prefix_len(id), self.buckets.len() - 1)
+    }
+
+    /// Records that `id` was heard from at `addr`. Returns false if the node
+    /// could not be placed in its bucket and was kept as a replacement.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        self.insert_at(id, addr, Instant::now())
+    }
+
+    fn insert_at(&mut self, id: NodeId, addr: SocketAddr, now: Instant) -> bool {
+        if id == self.local {
+            return false;
+        }
+        loop {
+            let i = self.bucket_index(&id);
+            let splittable = i == self.buckets.len() - 1 && self.buckets.len() < ID_BITS;
+            {
+                let b = &mut self.buckets[i];
+                if let Some(e) = b.nodes.iter_mut().find(|e| e.id == id) {
+                    *e = Entry { rtt: e.rtt, ..Entry::new(id, addr, now) };
+                    b.last_changed = now;
+                    return true;
+                }
+                if b.nodes.len() < K {
+                    b.nodes.push(Entry::new(id, addr, now));
+                    b.last_changed = now;
+                    return true;
+                }
+                if let Some(pos) = b.nodes.iter().position(|e| e.state(now) == NodeState::Bad) {
+                    b.nodes[pos] = Entry::new(id, addr, now);
+                    b.last_changed = now;
+                    return true;
+                }
+                if !splittable {
+                    b.add_replacement(Entry::new(id, addr, now));
+                    return false;
+                }
+            }
+            self.split(now);
+        }
+    }
+
+    /// Folds `sample` into the round-trip time of `id`, weighing it 1/8 as
+    /// TCP does (RFC 6298).
+    pub fn record_rtt(&mut self, id: &NodeId, sample: Duration) {
+        let i = self.bucket_index(id);
+        if let Some(e) = self.buckets[i].nodes.iter_mut().find(|e| e.id == *id) {
+            e.rtt = Some(match e.rtt {
+                Some(rtt) => rtt * 7 / 8 + sample / 8,
+                None => sample,
+            });
+        }
+    }
+
+    fn split(&mut self, now: Instant) {
+        let depth = self.buckets.len();
+        let mut next = Bucket::new(now);
+        {
+            let local = &self.local;
+            let last = &mut self.buckets[depth - 1];
+            let (far, near): (Vec<Entry>, Vec<Entry>) = last.nodes.drain(..)
+                .partition(|e| local.common_prefix_len(&e.id) < depth);
+            last.nodes = far;
+            next.nodes = near;
+            let (far, near): (Vec<Entry>, Vec<Entry>) = last.replacements.drain(..)
+                .partition(|e| local.common_prefix_len(&e.id) < depth);
+            last.replacements = far;
+            next.replacements = near;
+        }
+        self.buckets.push(next);
+    }
+
+    /// Up to `n` good or questionable nodes closest to `target`.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Entry> {
+        let now = Instant::now();
+        let mut all: Vec<Entry> = self.buckets.iter()
+            .flat_map(|b| b.nodes.iter())
+            .filter(|e| e.state(now) != NodeState::Bad)
+            .cloned()
+            .collect();
+        all.sort_by_key(|e| e.id.distance(target));
+        all.truncate(n);
+        all
+    }
+
+    /// Swaps bad nodes for replacements and returns the questionable nodes
+    /// that should be pinged now. A node that is still questionable on the
+    /// next call is counted as a failed ping.
+    pub fn refresh(&mut self) -> Vec<Entry> {
+        self.refresh_at(Instant::now())
+    }
+
+    fn refresh_at(&mut self, now: Instant) -> Vec<Entry> {
+        let mut to_ping = vec![];
+        for b in self.buckets.iter_mut() {
+            for e in b.nodes.iter_mut() {
+                if e.state(now) != NodeState::Questionable {
+                    continue;
+                }
+                if e.pinged {
+                    e.failed += 1;
+                }
+                e.pinged = true;
+                if e.state(now) == NodeState::Questionable {
+                    to_ping.push(e.clone());
+                }
+            }
+            for i in 0..b.nodes.len() {
+                if b.nodes[i].state(now) != NodeState::Bad {
+                    continue;
+                }
+                if let Some(r) = b.replacements.pop() {
+                    b.nodes[i] = r;
+                    b.last_changed = now;
+                }
+            }
+            b.nodes.retain(|e| e.state(now) != NodeState::Bad);
+        }
+        to_ping
+    }
+
+    /// Random targets inside every bucket that has not changed for 15 minutes,
+    /// to be looked up with find_node.
+    pub fn stale_targets(&mut self) -> Vec<NodeId> {
+        self.stale_targets_at(Instant::now())
+    }
+
+    fn stale_targets_at(&mut self, now: Instant) -> Vec<NodeId> {
+        let depth = self.buckets.len();
+        let mut targets = vec![];
+        for (i, b) in self.buckets.iter_mut().enumerate() {
+            if now.duration_since(b.last_changed) < GOOD_TIMEOUT {
+                continue;
+            }
+            b.last_changed = now;
+            let mut id = *NodeId::random().as_bytes();
+            let local = self.local.as_bytes();
+            // keep the first i bits of the local id, flip bit i unless this
+            // is the last bucket which also covers longer prefixes
+            let keep = if i == depth - 1 { i } else { i + 1 };
+            for bit in 0..keep {
+                let mask = 0x80 >> (bit % 8);
+                let mut v = local[bit / 8] & mask;
+                if bit == i {
+                    v ^= mask;
+                }
+                id[bit / 8] = (id[bit / 8] & !mask) | v;
+            }
+            targets.push(NodeId::from(id));
+        }
+        targets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// more than the table can hold
+    const ALL: usize = K * ID_BITS;
+
+    /// An ID sharing `prefix` bits with the all-zero local ID, `n` telling
+    /// IDs of the same prefix apart.
+    fn id(prefix: usize, n: u8) -> NodeId {
+        let mut b = [0; ID_LEN];
+        b[prefix / 8] = 0x80 >> (prefix % 8);
+        b[ID_LEN - 1] = n;
+        NodeId::from(b)
+    }
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, n], 6881))
+    }
+
+    /// A table whose first bucket is full of nodes sharing no prefix with the
+    /// local ID, split once.
+    fn full_table(now: Instant) -> Table {
+        let mut t = Table::new(NodeId::default());
+        for n in 0..K as u8 {
+            assert!(t.insert_at(id(0, n), addr(n), now));
+        }
+        assert_eq!(t.buckets.len(), 1);
+        t
+    }
+
+    #[test]
+    fn insert_and_split() {
+        let now = Instant::now();
+        let mut t = full_table(now);
+        assert!(!t.insert_at(NodeId::default(), addr(100), now));
+        // the only bucket covers the local ID, it is split to make room
+        assert!(t.insert_at(id(1, 0), addr(100), now));
+        assert_eq!(t.buckets.len(), 2);
+        assert_eq!(t.buckets[0].nodes.len(), K);
+        assert_eq!(t.buckets[1].nodes.len(), 1);
+        assert_eq!(t.nodes(), K + 1);
+        // the last bucket takes every longer prefix, and is split once full
+        for n in 1..K as u8 {
+            assert!(t.insert_at(id(1 + n as usize, 0), addr(n), now));
+        }
+        assert_eq!(t.buckets.len(), 2);
+        assert!(t.insert_at(id(1, 1), addr(101), now));
+        assert_eq!(t.buckets.len(), 3);
+        assert_eq!(t.buckets[1].nodes.len(), 2);
+        assert_eq!(t.buckets[2].nodes.len(), K - 1);
+        assert_eq!(t.nodes(), 2 * K + 1);
+        // known nodes are updated in place
+        assert!(t.insert_at(id(0, 0), addr(200), now));
+        assert_eq!(t.nodes(), 2 * K + 1);
+        assert_eq!(t.buckets[0].nodes[0].addr, addr(200));
+    }
+
+    #[test]
+    fn full_bucket() {
+        let now = Instant::now();
+        let mut t = full_table(now);
+        t.insert_at(id(1, 0), addr(100), now);
+        // the first bucket doesn't cover the local ID anymore
+        assert!(!t.insert_at(id(0, 100), addr(101), now));
+        assert!(!t.insert_at(id(0, 101), addr(102), now));
+        assert_eq!(t.buckets.len(), 2);
+        assert_eq!(t.buckets[0].nodes.len(), K);
+        assert_eq!(t.buckets[0].replacements.len(), 2);
+        assert!(t.closest(&id(0, 100), ALL).iter().all(|e| e.id != id(0, 100)));
+    }
+
+    #[test]
+    fn bad_node_replaced() {
+        let now = Instant::now();
+        let mut t = full_table(now);
+        t.insert_at(id(1, 0), addr(100), now);
+        t.buckets[0].nodes[3].failed = MAX_FAILED_PINGS;
+        assert!(t.insert_at(id(0, 100), addr(101), now));
+        assert_eq!(t.buckets[0].nodes.len(), K);
+        assert_eq!(t.buckets[0].nodes[3].id, id(0, 100));
+        assert!(t.buckets[0].replacements.is_empty());
+    }
+
+    #[test]
+    fn refresh() {
+        let now = Instant::now();
+        let mut t = Table::new(NodeId::default());
+        t.insert_at(id(0, 1), addr(1), now);
+        assert!(t.refresh_at(now).is_empty());
+
+        let later = now + GOOD_TIMEOUT;
+        assert_eq!(t.buckets[0].nodes[0].state(later), NodeState::Questionable);
+        for failed in 0..MAX_FAILED_PINGS {
+            let to_ping = t.refresh_at(later);
+            assert_eq!(to_ping.len(), 1);
+            assert_eq!(to_ping[0].id, id(0, 1));
+            assert_eq!(t.buckets[0].nodes[0].failed, failed);
+        }
+        // never answered, it is bad and dropped
+        assert!(t.refresh_at(later).is_empty());
+        assert_eq!(t.nodes(), 0);
+
+        // a bad node makes room for the latest replacement
+        let mut t = full_table(now);
+        t.insert_at(id(1, 0), addr(100), now);
+        t.insert_at(id(0, 100), addr(101), later);
+        t.buckets[0].nodes[5].failed = MAX_FAILED_PINGS;
+        t.refresh_at(now);
+        assert_eq!(t.buckets[0].nodes[5].id, id(0, 100));
+        assert!(t.buckets[0].replacements.is_empty());
+        assert_eq!(t.nodes(), K + 1);
+    }
+
+    #[test]
+    fn closest() {
+        let now = Instant::now();
+        let mut t = Table::new(NodeId::default());
+        for (i, prefix) in [0, 1, 2, 3, 4].iter().enumerate() {
+            t.insert_at(id(*prefix, 0), addr(i as u8), now);
+        }
+        let target = id(3, 1);
+        let closest: Vec<NodeId> = t.closest(&target, 3).iter().map(|e| e.id).collect();
+        assert_eq!(closest, vec![id(3, 0), id(4, 0), id(2, 0)]);
+
+        let all = t.closest(&target, ALL);
+        assert_eq!(all.len(), 5);
+        assert!(all.windows(2).all(|w| w[0].id.distance(&target) < w[1].id.distance(&target)));
+
+        t.buckets.iter_mut().flat_map(|b| b.nodes.iter_mut()).find(|e| e.id == id(3, 0)).unwrap().failed =
+            MAX_FAILED_PINGS;
+        assert_eq!(t.closest(&target, 1)[0].id, id(4, 0));
+    }
+
+    #[test]
+    fn rtt() {
+        let now = Instant::now();
+        let mut t = Table::new(NodeId::default());
+        t.insert_at(id(0, 1), addr(1), now);
+        t.insert_at(id(1, 1), addr(2), now);
+        assert_eq!(t.mean_rtt(), None);
+
+        t.record_rtt(&id(0, 1), Duration::from_millis(80));
+        assert_eq!(t.mean_rtt(), Some(Duration::from_millis(80)));
+        t.record_rtt(&id(0, 1), Duration::from_millis(160));
+        assert_eq!(t.buckets[0].nodes[0].rtt(), Some(Duration::from_millis(90)));
+        // hearing from the node again keeps its RTT
+        t.insert_at(id(0, 1), addr(1), now);
+        assert_eq!(t.buckets[0].nodes[0].rtt(), Some(Duration::from_millis(90)));
+
+        t.record_rtt(&id(1, 1), Duration::from_millis(30));
+        assert_eq!(t.mean_rtt(), Some(Duration::from_millis(60)));
+        // unknown nodes are ignored
+        t.record_rtt(&id(2, 1), Duration::from_secs(10));
+        assert_eq!(t.mean_rtt(), Some(Duration::from_millis(60)));
+    }
+
+    #[test]
+    fn stale_targets() {
+        let now = Instant::now();
+        let mut t = Table::new(NodeId::default());
+        for prefix in 0..3 {
+            for n in 0..K as u8 {
+                t.insert_at(id(prefix, n), addr(n), now);
+            }
+        }
+        t.insert_at(id(3, 0), addr(0), now);
+        assert_eq!(t.buckets.len(), 4);
+        assert!(t.stale_targets_at(now).is_empty());
+
+        let later = now + GOOD_TIMEOUT;
+        let targets = t.stale_targets_at(later);
+        assert_eq!(targets.len(), t.buckets.len());
+        let last = t.buckets.len() - 1;
+        for (i, target) in targets.iter().enumerate() {
+            let prefix = t.local.common_prefix_len(target);
+            if i == last {
+                assert!(prefix >= i);
+            } else {
+                assert_eq!(prefix, i);
+            }
+            assert_eq!(t.bucket_index(target), i);
+        }
+        // looked up, they are not stale anymore
+        assert!(t.stale_targets_at(later).is_empty());
+    }
+}
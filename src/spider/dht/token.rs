@@ -0,0 +1,134 @@
+//! Tokens handed out with get_peers and required by announce_peer (BEP 5).
+//!
+//! A token is a truncated SHA-1 of the querying IP and a random secret. The
+//! secret changes every `interval` and tokens of the previous one are still
+//! accepted, so a token lives between one and two intervals.
+
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::prelude::*;
+
+pub const DEFAULT_ROTATE_SEC: u64 = 300;
+const SECRET_LEN: usize = 20;
+const TOKEN_LEN: usize = 8;
+
+struct Secrets {
+    current: [u8; SECRET_LEN],
+    previous: [u8; SECRET_LEN],
+    rotated: Instant,
+}
+
+pub struct Tokens {
+    /// configured secret, mixed in with the random ones
+    seed: Vec<u8>,
+    interval: Duration,
+    secrets: Mutex<Secrets>,
+}
+
+fn random_secret() -> [u8; SECRET_LEN] {
+    let mut s = [0; SECRET_LEN];
+    thread_rng().fill_bytes(&mut s);
+    s
+}
+
+impl Tokens {
+    pub fn new(seed: &str, interval: Duration) -> Tokens {
+        Tokens {
+            seed: seed.as_bytes().to_vec(),
+            interval,
+            secrets: Mutex::new(Secrets { current: random_secret(), previous: random_secret(), rotated: Instant::now() }),
+        }
+    }
+
+    /// Token of `ip` under the current secret.
+    pub fn generate(&self, ip: IpAddr) -> Vec<u8> {
+        let secret = self.secrets_at(Instant::now()).0;
+        self.token(&secret, ip).to_vec()
+    }
+
+    /// Whether `token` was given to `ip` under the current or the previous
+    /// secret.
+    pub fn verify(&self, token: &[u8], ip: IpAddr) -> bool {
+        self.verify_at(token, ip, Instant::now())
+    }
+
+    fn verify_at(&self, token: &[u8], ip: IpAddr, now: Instant) -> bool {
+        let (current, previous) = self.secrets_at(now);
+        // both are checked so that the time taken doesn't tell which matched
+        let a = constant_time_eq(&self.token(&current, ip), token);
+        let b = constant_time_eq(&self.token(&previous, ip), token);
+        a | b
+    }
+
+    /// Current and previous secrets at `now`, rotated as many times as
+    /// intervals went by.
+    fn secrets_at(&self, now: Instant) -> ([u8; SECRET_LEN], [u8; SECRET_LEN]) {
+        let mut s = self.secrets.lock().unwrap();
+        let elapsed = now.saturating_duration_since(s.rotated);
+        if elapsed >= self.interval * 2 {
+            s.previous = random_secret();
+            s.current = random_secret();
+            s.rotated = now;
+        } else if elapsed >= self.interval {
+            s.previous = s.current;
+            s.current = random_secret();
+            s.rotated += self.interval;
+        }
+        (s.current, s.previous)
+    }
+
+    fn token(&self, secret: &[u8], ip: IpAddr) -> [u8; TOKEN_LEN] {
+        let mut h = sha1::Sha1::new();
+        h.update(secret);
+        h.update(&self.seed);
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => h.update(&ip.octets()),
+            IpAddr::V6(ip) => h.update(&ip.octets()),
+        }
+        let mut t = [0; TOKEN_LEN];
+        t.copy_from_slice(&h.digest().bytes()[..TOKEN_LEN]);
+        t
+    }
+}
+
+/// Compares without stopping at the first difference. Only the length may
+/// leak, and it is public.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        let tokens = Tokens::new("seed", Duration::from_secs(300));
+        let (ip, other) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let t = tokens.generate(ip);
+        assert_eq!(t.len(), TOKEN_LEN);
+        assert!(tokens.verify(&t, ip));
+        assert!(tokens.verify(&t, "::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!tokens.verify(&t, other));
+        assert!(!tokens.verify(&t[..TOKEN_LEN - 1], ip));
+        assert!(!tokens.verify(b"", ip));
+
+        let start = tokens.secrets.lock().unwrap().rotated;
+        assert!(tokens.verify_at(&t, ip, start + Duration::from_secs(299)));
+        // rotated once, the token is from the previous secret
+        assert!(tokens.verify_at(&t, ip, start + Duration::from_secs(300)));
+        assert!(tokens.verify_at(&t, ip, start + Duration::from_secs(599)));
+        assert!(!tokens.verify_at(&t, ip, start + Duration::from_secs(600)));
+
+        // idle for long, both secrets are new
+        let tokens = Tokens::new("seed", Duration::from_secs(300));
+        let t = tokens.generate(ip);
+        let start = tokens.secrets.lock().unwrap().rotated;
+        assert!(!tokens.verify_at(&t, ip, start + Duration::from_secs(1000)));
+    }
+}
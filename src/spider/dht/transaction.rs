@@ -0,0 +1,142 @@
+//! Queries we sent and are waiting for an answer to.
+//!
+//! Each query gets a two-byte transaction ID that the response echoes back.
+//! A response is only taken if it answers a pending query and comes from the
+//! address the query went to, the rest is dropped.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rand::prelude::*;
+
+use super::krpc::Query;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// queries waiting at once, more are not sent until `expire` makes room
+pub const MAX_PENDING: usize = 16384;
+
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub query: Query,
+    pub to: SocketAddr,
+    pub sent: Instant,
+}
+
+/// Why a response or error reply was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unmatched {
+    /// no pending query has its transaction ID
+    Unsolicited,
+    /// the query went to another address
+    Mismatched,
+    /// the query already timed out
+    Late,
+}
+
+impl Unmatched {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Unmatched::Unsolicited => "unsolicited",
+            Unmatched::Mismatched => "mismatched",
+            Unmatched::Late => "late",
+        }
+    }
+}
+
+pub struct Transactions {
+    timeout: Duration,
+    next: u16,
+    pending: HashMap<u16, Pending>,
+}
+
+impl Transactions {
+    pub fn new(timeout: Duration) -> Transactions {
+        Transactions { timeout, next: random(), pending: HashMap::new() }
+    }
+
+    /// Records `query` sent to `to` and returns its transaction ID, `None` if
+    /// too many queries are pending.
+    pub fn start(&mut self, query: Query, to: SocketAddr, now: Instant) -> Option<Vec<u8>> {
+        if self.pending.len() >= MAX_PENDING {
+            return None;
+        }
+        while self.pending.contains_key(&self.next) {
+            self.next = self.next.wrapping_add(1);
+        }
+        let t = self.next;
+        self.next = self.next.wrapping_add(1);
+        self.pending.insert(t, Pending { query, to, sent: now });
+        Some(t.to_be_bytes().to_vec())
+    }
+
+    /// Ends the transaction `t` answered by `from`. A mismatched answer
+    /// leaves it pending, the right node may still answer.
+    pub fn finish(&mut self, t: &[u8], from: SocketAddr, now: Instant) -> Result<Pending, Unmatched> {
+        let key = match *t {
+            [a, b] => u16::from_be_bytes([a, b]),
+            _ => return Err(Unmatched::Unsolicited),
+        };
+        match self.pending.get(&key) {
+            None => return Err(Unmatched::Unsolicited),
+            Some(p) if p.to != from => return Err(Unmatched::Mismatched),
+            Some(_) => {}
+        }
+        let p = self.pending.remove(&key).ok_or(Unmatched::Unsolicited)?;
+        if now.saturating_duration_since(p.sent) > self.timeout {
+            return Err(Unmatched::Late);
+        }
+        Ok(p)
+    }
+
+    /// Drops the queries that timed out and returns them.
+    pub fn expire(&mut self, now: Instant) -> Vec<Pending> {
+        let timeout = self.timeout;
+        let mut expired = vec![];
+        self.pending.retain(|_, p| {
+            let keep = now.saturating_duration_since(p.sent) <= timeout;
+            if !keep {
+                expired.push(p.clone());
+            }
+            keep
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::id::NodeId;
+
+    #[test]
+    fn match_responses() {
+        let mut tr = Transactions::new(Duration::from_secs(10));
+        let now = Instant::now();
+        let (a, b): (SocketAddr, SocketAddr) = ("192.0.2.1:6881".parse().unwrap(), "192.0.2.2:6881".parse().unwrap());
+        let ping = Query::Ping { id: NodeId::random() };
+        let t1 = tr.start(ping.clone(), a, now).unwrap();
+        let t2 = tr.start(ping.clone(), b, now).unwrap();
+        assert_eq!(t1.len(), 2);
+        assert_ne!(t1, t2);
+
+        assert_eq!(tr.finish(&t1, b, now).unwrap_err(), Unmatched::Mismatched);
+        let p = tr.finish(&t1, a, now + Duration::from_secs(1)).unwrap();
+        assert_eq!((p.query, p.to), (ping.clone(), a));
+        assert_eq!(tr.finish(&t1, a, now).unwrap_err(), Unmatched::Unsolicited);
+        assert_eq!(tr.finish(b"", a, now).unwrap_err(), Unmatched::Unsolicited);
+        assert_eq!(tr.finish(&t2, b, now + Duration::from_secs(11)).unwrap_err(), Unmatched::Late);
+
+        tr.start(ping.clone(), a, now).unwrap();
+        assert!(tr.expire(now + Duration::from_secs(10)).is_empty());
+        assert_eq!(tr.expire(now + Duration::from_secs(11)).len(), 1);
+        assert_eq!(tr.pending.len(), 0);
+
+        for _ in 0..MAX_PENDING {
+            tr.start(ping.clone(), a, now).unwrap();
+        }
+        assert!(tr.start(ping.clone(), a, now).is_none());
+        assert_eq!(tr.expire(now + Duration::from_secs(11)).len(), MAX_PENDING);
+        assert!(tr.start(ping, a, now).is_some());
+    }
+}
@@ -0,0 +1,206 @@
+//! Worker threads for the blocking metadata fetches, fed by a bounded queue.
+//!
+//! Announces arrive faster than peers answer, so the queue drops new jobs
+//! when it is full rather than growing. Each worker keeps statistics of its
+//! jobs, read with `Pool::stats` and `Pool::total`.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// Outcome of the jobs handled by one worker.
+#[derive(Debug, Clone, Default)]
+pub struct WorkerStats {
+    pub fetched: u64,
+    pub failed: u64,
+    /// sum of the latency of every job, successful or not
+    pub total_millis: u64,
+    pub max_millis: u64,
+}
+
+impl WorkerStats {
+    pub fn jobs(&self) -> u64 {
+        self.fetched + self.failed
+    }
+
+    /// Share of successful jobs, 0 before the first one.
+    pub fn success_rate(&self) -> f64 {
+        if self.jobs() == 0 {
+            return 0.0;
+        }
+        self.fetched as f64 / self.jobs() as f64
+    }
+
+    pub fn mean_millis(&self) -> u64 {
+        if self.jobs() == 0 {
+            return 0;
+        }
+        self.total_millis / self.jobs()
+    }
+
+    fn record(&mut self, ok: bool, millis: u64) {
+        if ok {
+            self.fetched += 1;
+        } else {
+            self.failed += 1;
+        }
+        self.total_millis += millis;
+        self.max_millis = self.max_millis.max(millis);
+    }
+}
+
+impl fmt::Display for WorkerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{} ok ({:.1}%), avg {} ms, max {} ms",
+               self.fetched, self.jobs(), self.success_rate() * 100.0, self.mean_millis(), self.max_millis)
+    }
+}
+
+/// A fixed number of worker threads handling jobs from a bounded queue.
+///
+/// Metadata fetches block on the peer for up to the wire timeout, so each one
+/// gets its own thread and a slow peer only holds up its own worker.
+pub struct Pool<T> {
+    queue: mpsc::SyncSender<T>,
+    stats: Arc<Vec<Mutex<WorkerStats>>>,
+    dropped: AtomicU64,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// Starts `workers` threads calling `handler` for every job submitted.
+    pub fn start<F>(workers: usize, queue_len: usize, handler: F) -> Pool<T>
+        where F: Fn(T) -> Result<(), String> + Send + Sync + 'static
+    {
+        let (queue, rx) = mpsc::sync_channel(queue_len);
+        let rx = Arc::new(Mutex::new(rx));
+        let handler = Arc::new(handler);
+        let stats = Arc::new((0..workers).map(|_| Mutex::new(WorkerStats::default())).collect::<Vec<_>>());
+        for i in 0..workers {
+            let rx = rx.clone();
+            let handler = handler.clone();
+            let stats = stats.clone();
+            thread::spawn(move || loop {
+                let job = match rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                let started = Instant::now();
+                let ok = handler(job).is_ok();
+                let millis = started.elapsed().as_millis() as u64;
+                stats[i].lock().unwrap().record(ok, millis);
+            });
+        }
+        Pool { queue, stats, dropped: AtomicU64::new(0) }
+    }
+
+    /// Queues `job`, dropping it if every worker is busy and the queue is
+    /// full. Returns whether it was queued.
+    pub fn submit(&self, job: T) -> bool {
+        if self.queue.try_send(job).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Jobs dropped by `submit` so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Snapshot of the statistics of every worker.
+    pub fn stats(&self) -> Vec<WorkerStats> {
+        self.stats.iter().map(|s| s.lock().unwrap().clone()).collect()
+    }
+
+    /// Statistics of all the workers together.
+    pub fn total(&self) -> WorkerStats {
+        let mut total = WorkerStats::default();
+        for s in self.stats() {
+            total.fetched += s.fetched;
+            total.failed += s.failed;
+            total.total_millis += s.total_millis;
+            total.max_millis = total.max_millis.max(s.max_millis);
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn full_queue() {
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let pool = Pool::start(1, 1, move |job: u32| {
+            started_tx.send(job).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            if job.is_multiple_of(2) { Ok(()) } else { Err("odd".to_string()) }
+        });
+        assert!(pool.submit(1));
+        assert_eq!(started.recv().unwrap(), 1);
+        // the worker is busy, one job fits in the queue
+        assert!(pool.submit(2));
+        assert!(!pool.submit(3));
+        assert!(!pool.submit(4));
+        assert_eq!(pool.dropped(), 2);
+
+        release.send(()).unwrap();
+        assert_eq!(started.recv().unwrap(), 2);
+        release.send(()).unwrap();
+        while pool.total().jobs() < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let total = pool.total();
+        assert_eq!((total.fetched, total.failed), (1, 1));
+    }
+
+    #[test]
+    fn stats() {
+        let pool = Pool::start(2, 1, |_: ()| Ok(()));
+        assert_eq!(pool.total().success_rate(), 0.0);
+        assert_eq!(pool.total().mean_millis(), 0);
+        pool.stats[0].lock().unwrap().record(true, 100);
+        pool.stats[0].lock().unwrap().record(false, 300);
+        pool.stats[1].lock().unwrap().record(true, 20);
+        pool.stats[1].lock().unwrap().record(true, 60);
+
+        let stats = pool.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].jobs(), stats[0].mean_millis(), stats[0].max_millis), (2, 200, 300));
+        let total = pool.total();
+        assert_eq!((total.fetched, total.failed, total.total_millis, total.max_millis), (3, 1, 480, 300));
+        assert_eq!(total.success_rate(), 0.75);
+        assert_eq!(total.to_string(), "3/4 ok (75.0%), avg 120 ms, max 300 ms");
+    }
+
+    #[test]
+    fn shutdown() {
+        let done = Arc::new(AtomicU64::new(0));
+        let d = done.clone();
+        let pool = Pool::start(2, 4, move |_: ()| {
+            d.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        });
+        for _ in 0..4 {
+            assert!(pool.submit(()));
+        }
+        // the workers finish the queued jobs, then exit and drop the handler
+        drop(pool);
+        for _ in 0..1000 {
+            if Arc::strong_count(&done) == 1 {
+                assert_eq!(done.load(Ordering::Relaxed), 4);
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("the workers are still running");
+    }
+}
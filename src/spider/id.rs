@@ -0,0 +1,255 @@
+//! 160-bit identifiers of the DHT and the BitTorrent wire protocol.
+
+use rand::prelude::*;
+use std::fmt;
+use std::str::FromStr;
+
+pub const ID_LEN: usize = 20;
+
+static HEX_CHARS: &[u8] = b"0123456789abcdef";
+static BASE32_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+macro_rules! id_type {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+        pub struct $name([u8; ID_LEN]);
+
+        impl $name {
+            /// None unless `b` is exactly 20 bytes long.
+            pub fn from_bytes(b: &[u8]) -> Option<$name> {
+                if b.len() != ID_LEN {
+                    return None;
+                }
+                let mut r = [0; ID_LEN];
+                r.copy_from_slice(b);
+                Some($name(r))
+            }
+
+            pub fn from_hex(s: &str) -> Option<$name> {
+                let s = s.as_bytes();
+                if s.len() != ID_LEN * 2 {
+                    return None;
+                }
+                let mut r = [0; ID_LEN];
+                for (i, pair) in s.chunks(2).enumerate() {
+                    r[i] = (hex_value(pair[0])? << 4) | hex_value(pair[1])?;
+                }
+                Some($name(r))
+            }
+
+            /// RFC 4648 base32 as used by magnet links, case insensitive.
+            pub fn from_base32(s: &str) -> Option<$name> {
+                let s = s.as_bytes();
+                if s.len() != ID_LEN * 8 / 5 {
+                    return None;
+                }
+                let mut r = [0; ID_LEN];
+                let mut acc: u32 = 0;
+                let mut bits = 0;
+                let mut i = 0;
+                for &c in s.iter() {
+                    let v = BASE32_CHARS.iter().position(|&b| b == c.to_ascii_uppercase())?;
+                    acc = (acc << 5) | v as u32;
+                    bits += 5;
+                    if bits >= 8 {
+                        bits -= 8;
+                        r[i] = (acc >> bits) as u8;
+                        i += 1;
+                    }
+                }
+                Some($name(r))
+            }
+
+            pub fn as_bytes(&self) -> &[u8; ID_LEN] {
+                &self.0
+            }
+
+            pub fn to_hex(self) -> String {
+                let mut s = String::with_capacity(ID_LEN * 2);
+                for &b in self.0.iter() {
+                    s.push(HEX_CHARS[(b >> 4) as usize] as char);
+                    s.push(HEX_CHARS[(b & 0xf) as usize] as char);
+                }
+                s
+            }
+
+        }
+
+        impl From<[u8; ID_LEN]> for $name {
+            fn from(b: [u8; ID_LEN]) -> $name {
+                $name(b)
+            }
+        }
+
+        /// Accepts 40 hex or 32 base32 characters.
+        impl FromStr for $name {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<$name, String> {
+                $name::from_hex(s)
+                    .or_else(|| $name::from_base32(s))
+                    .ok_or_else(|| format!("invalid {}: {}", stringify!($name), s))
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(&self.to_hex())
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self.to_hex())
+            }
+        }
+    };
+}
+
+id_type!(
+    /// ID of a DHT node.
+    NodeId
+);
+
+id_type!(
+    /// SHA-1 of the info dictionary of a torrent.
+    InfoHash
+);
+
+fn random_bytes() -> [u8; ID_LEN] {
+    let mut b = [0; ID_LEN];
+    thread_rng().fill(&mut b[..]);
+    b
+}
+
+impl NodeId {
+    pub fn random() -> NodeId {
+        NodeId(random_bytes())
+    }
+
+    /// XOR metric of Kademlia, closer IDs compare smaller.
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut r = [0; ID_LEN];
+        for (i, b) in r.iter_mut().enumerate() {
+            *b = self.0[i] ^ other.0[i];
+        }
+        NodeId(r)
+    }
+
+    /// Number of leading bits shared with `other`.
+    pub fn common_prefix_len(&self, other: &NodeId) -> usize {
+        for (i, (x, y)) in self.0.iter().zip(other.0.iter()).enumerate() {
+            let d = x ^ y;
+            if d != 0 {
+                return i * 8 + d.leading_zeros() as usize;
+            }
+        }
+        ID_LEN * 8
+    }
+
+    /// An ID sharing its first half with `target` so that `target` considers
+    /// us a close neighbour, the second half is taken from `self`.
+    pub fn neighbour(&self, target: &NodeId) -> NodeId {
+        let mut r = self.0;
+        r[..ID_LEN / 2].copy_from_slice(&target.0[..ID_LEN / 2]);
+        NodeId(r)
+    }
+}
+
+#[cfg(test)]
+impl InfoHash {
+    pub fn random() -> InfoHash {
+        InfoHash(random_bytes())
+    }
+}
+
+/// Info hashes and node IDs share the same key space.
+impl From<InfoHash> for NodeId {
+    fn from(h: InfoHash) -> NodeId {
+        NodeId(h.0)
+    }
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+    const BASE32: &str = "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+
+    #[test]
+    fn parse() {
+        let h = InfoHash::from_hex(HEX).unwrap();
+        assert_eq!(h.as_bytes()[..2], [0xc1, 0x2f]);
+        assert_eq!(h.to_hex(), HEX);
+        assert_eq!(InfoHash::from_hex(&HEX.to_uppercase()), Some(h));
+        assert_eq!(InfoHash::from_hex("C12FE1C06BBA254A9DC9f519b335aa7c1367a88a"), Some(h));
+        assert_eq!(InfoHash::from_hex(&HEX[..38]), None);
+        assert_eq!(InfoHash::from_hex(&format!("{}00", HEX)), None);
+        assert_eq!(InfoHash::from_hex(&HEX.replace('c', "g")), None);
+
+        assert_eq!(InfoHash::from_base32(BASE32), Some(h));
+        assert_eq!(InfoHash::from_base32(&BASE32.to_lowercase()), Some(h));
+        assert_eq!(InfoHash::from_base32("yex6dqdlXISUVHOJ6UM3GNNKPQJWPKEK"), Some(h));
+        assert_eq!(InfoHash::from_base32(&BASE32[..31]), None);
+        assert_eq!(InfoHash::from_base32(&BASE32.replace('Y', "1")), None);
+
+        assert_eq!(HEX.parse::<InfoHash>(), Ok(h));
+        assert_eq!(BASE32.parse::<InfoHash>(), Ok(h));
+        assert_eq!("nope".parse::<NodeId>(), Err("invalid NodeId: nope".to_string()));
+        assert_eq!(NodeId::from_bytes(h.as_bytes()), Some(NodeId::from(h)));
+        assert_eq!(NodeId::from_bytes(&h.as_bytes()[1..]), None);
+        assert_eq!(format!("{:?}", h), format!("InfoHash({})", HEX));
+    }
+
+    #[test]
+    fn distance() {
+        let a = NodeId::from([0xf0; ID_LEN]);
+        let b = NodeId::from([0x0f; ID_LEN]);
+        assert_eq!(a.distance(&a), NodeId::default());
+        assert_eq!(a.distance(&b), NodeId::from([0xff; ID_LEN]));
+        assert_eq!(a.distance(&b), b.distance(&a));
+
+        let mut near = *a.as_bytes();
+        near[ID_LEN - 1] ^= 1;
+        let near = NodeId::from(near);
+        assert!(a.distance(&near) < a.distance(&b));
+    }
+
+    #[test]
+    fn common_prefix_len() {
+        let a = NodeId::default();
+        assert_eq!(a.common_prefix_len(&a), ID_LEN * 8);
+        let mut b = [0; ID_LEN];
+        b[0] = 0x80;
+        assert_eq!(a.common_prefix_len(&NodeId::from(b)), 0);
+        b[0] = 0x01;
+        assert_eq!(a.common_prefix_len(&NodeId::from(b)), 7);
+        b[0] = 0;
+        b[2] = 0x20;
+        assert_eq!(a.common_prefix_len(&NodeId::from(b)), 18);
+        b[2] = 0;
+        b[ID_LEN - 1] = 1;
+        assert_eq!(a.common_prefix_len(&NodeId::from(b)), ID_LEN * 8 - 1);
+    }
+
+    #[test]
+    fn neighbour() {
+        let local = NodeId::from([0x11; ID_LEN]);
+        let target = NodeId::from([0xee; ID_LEN]);
+        let n = local.neighbour(&target);
+        assert_eq!(n.as_bytes()[..ID_LEN / 2], target.as_bytes()[..ID_LEN / 2]);
+        assert_eq!(n.as_bytes()[ID_LEN / 2..], local.as_bytes()[ID_LEN / 2..]);
+        assert!(target.common_prefix_len(&n) >= ID_LEN * 4);
+    }
+}
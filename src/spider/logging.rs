@@ -0,0 +1,30 @@
+//! Sets up where the `tracing` events go: stderr, one line per event by
+//! default, spread over several lines with `pretty` or as JSON objects with
+//! `json`.
+//!
+//! The filter takes `tracing_subscriber::EnvFilter` directives, e.g.
+//! `info,p2pspider::spider::dht=debug` to follow every KRPC message.
+
+use std::io::{self, IsTerminal};
+
+use tracing_subscriber::EnvFilter;
+
+pub const FORMATS: [&str; 3] = ["text", "pretty", "json"];
+pub const DEFAULT_FORMAT: &str = "text";
+pub const DEFAULT_FILTER: &str = "info";
+
+/// Installs the global subscriber. `format` is one of `FORMATS`.
+pub fn init(format: &str, filter: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(filter).map_err(|e| format!("invalid log filter {:?}: {}", filter, e))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    let result = match format {
+        "text" => builder.try_init(),
+        "pretty" => builder.pretty().try_init(),
+        "json" => builder.json().try_init(),
+        _ => return Err(format!("unknown log format {:?}, expected one of {:?}", format, FORMATS)),
+    };
+    result.map_err(|e| e.to_string())
+}
@@ -0,0 +1,189 @@
+//! Counters and histograms of the DHT node and the metadata fetches, served in
+//! the Prometheus text format on `GET /metrics`.
+
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+
+/// upper bounds of the fetch latency buckets, in seconds
+const FETCH_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0];
+/// upper bounds of the KRPC round-trip time buckets, in seconds
+const RTT_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.2, 0.4, 0.8, 1.6, 3.2, 10.0];
+
+pub struct Metrics {
+    registry: Registry,
+    /// KRPC packets received by method, `response`, `error` or `invalid`
+    pub packets: IntCounterVec,
+    /// `krpc` packets and `announce`s ignored because their sender is
+    /// blocked
+    pub blocked: IntCounterVec,
+    /// peers banned for failing too often
+    pub bans: IntCounter,
+    /// `response` or `error` sent back to a query
+    pub replies: IntCounterVec,
+    /// our queries by outcome: `answered`, `error`, `timeout` or `dropped`
+    /// before sending, and answers dropped as `unsolicited`, `mismatched` or
+    /// `late`, see `Unmatched`
+    pub transactions: IntCounterVec,
+    pub rtt_seconds: Histogram,
+    /// sends held back by the rate limit, by `Traffic`: queries wait, replies
+    /// are dropped
+    pub rate_limited: IntCounterVec,
+    /// announce_peer queries `accepted`, `rejected` for a bad token or
+    /// `dropped` because the consumer is behind
+    pub announces: IntCounterVec,
+    /// nodes queued for a find_node by the make-friends task
+    pub nodes_queued: IntCounter,
+    /// announces by what the seen filter made of them, see `Claim`
+    pub dedupe: IntCounterVec,
+    /// `announce`s dropped by info hash and `torrent`s by content, see
+    /// `rules`
+    pub filtered: IntCounterVec,
+    /// fetches by outcome, `ok` or the kind of error, see `WireError::kind`
+    pub fetches: IntCounterVec,
+    pub fetch_seconds: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let packets = IntCounterVec::new(
+            Opts::new("p2pspider_krpc_packets_received_total", "KRPC packets received by method"),
+            &["method"]).expect("valid metric");
+        let blocked = IntCounterVec::new(
+            Opts::new("p2pspider_blocked_total", "Traffic ignored because its sender is blocked"),
+            &["traffic"]).expect("valid metric");
+        let bans = IntCounter::new(
+            "p2pspider_bans_total", "Peers banned for failing too often").expect("valid metric");
+        let replies = IntCounterVec::new(
+            Opts::new("p2pspider_krpc_replies_sent_total", "Replies sent to KRPC queries"),
+            &["type"]).expect("valid metric");
+        let transactions = IntCounterVec::new(
+            Opts::new("p2pspider_krpc_transactions_total", "Queries sent by outcome and answers dropped"),
+            &["result"]).expect("valid metric");
+        let rtt_seconds = Histogram::with_opts(
+            HistogramOpts::new("p2pspider_krpc_rtt_seconds", "Round-trip time of answered queries")
+                .buckets(RTT_BUCKETS.to_vec())).expect("valid metric");
+        let rate_limited = IntCounterVec::new(
+            Opts::new("p2pspider_krpc_rate_limited_total", "Queries delayed and replies dropped by the rate limit"),
+            &["traffic"]).expect("valid metric");
+        let announces = IntCounterVec::new(
+            Opts::new("p2pspider_announces_total", "announce_peer queries by result"),
+            &["result"]).expect("valid metric");
+        let nodes_queued = IntCounter::new(
+            "p2pspider_nodes_queued_total", "Nodes queued for a find_node").expect("valid metric");
+        let dedupe = IntCounterVec::new(
+            Opts::new("p2pspider_dedupe_total", "Announces by whether their torrent was new, held or being fetched"),
+            &["result"]).expect("valid metric");
+        let filtered = IntCounterVec::new(
+            Opts::new("p2pspider_filtered_total", "Announces and torrents dropped by the rules"),
+            &["stage"]).expect("valid metric");
+        let fetches = IntCounterVec::new(
+            Opts::new("p2pspider_fetches_total", "Metadata fetches by outcome"),
+            &["outcome"]).expect("valid metric");
+        let fetch_seconds = Histogram::with_opts(
+            HistogramOpts::new("p2pspider_fetch_duration_seconds", "Duration of metadata fetches")
+                .buckets(FETCH_BUCKETS.to_vec())).expect("valid metric");
+
+        let registry = Registry::new();
+        registry.register(Box::new(packets.clone())).expect("unique metric");
+        registry.register(Box::new(blocked.clone())).expect("unique metric");
+        registry.register(Box::new(bans.clone())).expect("unique metric");
+        registry.register(Box::new(replies.clone())).expect("unique metric");
+        registry.register(Box::new(transactions.clone())).expect("unique metric");
+        registry.register(Box::new(rtt_seconds.clone())).expect("unique metric");
+        registry.register(Box::new(rate_limited.clone())).expect("unique metric");
+        registry.register(Box::new(announces.clone())).expect("unique metric");
+        registry.register(Box::new(nodes_queued.clone())).expect("unique metric");
+        registry.register(Box::new(dedupe.clone())).expect("unique metric");
+        registry.register(Box::new(filtered.clone())).expect("unique metric");
+        registry.register(Box::new(fetches.clone())).expect("unique metric");
+        registry.register(Box::new(fetch_seconds.clone())).expect("unique metric");
+        Metrics { registry, packets, blocked, bans, replies, transactions, rtt_seconds, rate_limited, announces, nodes_queued, dedupe, filtered, fetches, fetch_seconds }
+    }
+
+    /// Records a fetch that took `elapsed`, `outcome` being `ok` or an error
+    /// kind.
+    pub fn fetched(&self, outcome: &str, elapsed: Duration) {
+        self.fetches.with_label_values(&[outcome]).inc();
+        self.fetch_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buf = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf).expect("writing to a Vec");
+        String::from_utf8(buf).expect("utf-8 metrics")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+pub struct Exporter {
+    server: tiny_http::Server,
+    metrics: Arc<Metrics>,
+}
+
+/// Binds the HTTP endpoint serving `metrics`.
+pub fn bind(addr: &str, metrics: Arc<Metrics>) -> Result<Exporter, io::Error> {
+    let server = tiny_http::Server::http(addr).map_err(io::Error::other)?;
+    Ok(Exporter { server, metrics })
+}
+
+impl Exporter {
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    pub fn start(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for req in self.server.incoming_requests() {
+                let resp = if req.url() == "/metrics" {
+                    let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], TextEncoder::new().format_type())
+                        .expect("static header");
+                    tiny_http::Response::from_string(self.metrics.encode()).with_header(header)
+                } else {
+                    tiny_http::Response::from_string("not found").with_status_code(404)
+                };
+                let _ = req.respond(resp);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn serve_metrics() {
+        let m = Arc::new(Metrics::new());
+        m.packets.with_label_values(&["get_peers"]).inc_by(3);
+        m.announces.with_label_values(&["rejected"]).inc();
+        m.fetched("ok", Duration::from_millis(300));
+        m.fetched("timeout", Duration::from_secs(30));
+
+        let exporter = bind("127.0.0.1:0", m.clone()).unwrap();
+        let addr = exporter.local_addr().unwrap();
+        exporter.start();
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut body = String::new();
+        conn.read_to_string(&mut body).unwrap();
+
+        assert!(body.starts_with("HTTP/1.0 200"));
+        assert!(body.contains("p2pspider_krpc_packets_received_total{method=\"get_peers\"} 3\n"));
+        assert!(body.contains("p2pspider_announces_total{result=\"rejected\"} 1\n"));
+        assert!(body.contains("p2pspider_fetches_total{outcome=\"timeout\"} 1\n"));
+        assert!(body.contains("p2pspider_fetch_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(body.contains("p2pspider_fetch_duration_seconds_count 2\n"));
+    }
+}
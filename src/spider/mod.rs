@@ -0,0 +1,15 @@
+pub mod api ;
+pub mod blocklist ;
+pub mod catalog ;
+pub mod config ;
+pub mod dedupe ;
+pub mod dht ;
+pub mod fetcher ;
+pub mod id ;
+pub mod logging ;
+pub mod metrics ;
+pub mod rules ;
+pub mod store ;
+#[cfg(test)]
+mod testutil ;
+pub mod wire ;
\ No newline at end of file
@@ -0,0 +1,262 @@
+//! Moderation rules: announces dropped by info hash before they are fetched,
+//! and torrents dropped by content before they are stored.
+//!
+//! The rules are a TOML file, reloaded when it changes:
+//!
+//! ```toml
+//! info_hashes = ["c12fe1c06bba254a9dc9f519b335aa7c1367a88a"]
+//!
+//! # a torrent is dropped when it matches every condition of any rule
+//! [[drop]]
+//! name = "(?i)\\bsample\\b"
+//! [[drop]]
+//! extensions = ["exe", "scr"]
+//! [[drop]]
+//! max_size = 1048576
+//! max_files = 1
+//! ```
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use regex::Regex;
+use serde_derive::Deserialize;
+use tracing::info;
+
+use super::id::InfoHash;
+use super::wire::Torrent;
+
+pub const DEFAULT_RELOAD_SEC: u64 = 10;
+/// info hashes dropped by content that are remembered, so that they are not
+/// fetched again on every announce
+const MAX_DROPPED: usize = 100_000;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RulesFile {
+    info_hashes: Vec<String>,
+    drop: Vec<RuleFile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RuleFile {
+    name: Option<String>,
+    extensions: Vec<String>,
+    min_size: Option<i64>,
+    max_size: Option<i64>,
+    min_files: Option<usize>,
+    max_files: Option<usize>,
+}
+
+/// Conditions on a torrent, all of the given ones must hold for it to match.
+#[derive(Debug)]
+pub struct Rule {
+    /// searched in the torrent name
+    name: Option<Regex>,
+    /// lowercase, without the dot; matches if any file has one of them
+    extensions: Vec<String>,
+    min_size: Option<i64>,
+    max_size: Option<i64>,
+    min_files: Option<usize>,
+    max_files: Option<usize>,
+}
+
+impl Rule {
+    pub fn matches(&self, t: &Torrent) -> bool {
+        if let Some(ref re) = self.name {
+            if !re.is_match(&t.name) {
+                return false;
+            }
+        }
+        if !self.extensions.is_empty() && !t.files.iter().any(|f| self.extensions.contains(&extension(&f.name))) {
+            return false;
+        }
+        self.min_size.is_none_or(|n| t.length >= n)
+            && self.max_size.is_none_or(|n| t.length <= n)
+            && self.min_files.is_none_or(|n| t.files.len() >= n)
+            && self.max_files.is_none_or(|n| t.files.len() <= n)
+    }
+}
+
+fn extension(path: &str) -> String {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    match file.rfind('.') {
+        Some(i) if i > 0 => file[i + 1..].to_lowercase(),
+        _ => String::new(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Rules {
+    info_hashes: HashSet<InfoHash>,
+    drop: Vec<Rule>,
+}
+
+/// Parses a rules file, see the module documentation.
+pub fn parse(text: &str) -> Result<Rules, String> {
+    let f: RulesFile = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut rules = Rules::default();
+    for h in f.info_hashes.iter() {
+        rules.info_hashes.insert(h.parse().map_err(|e| format!("info_hashes: {}", e))?);
+    }
+    for (i, r) in f.drop.into_iter().enumerate() {
+        let err = |e: String| format!("drop #{}: {}", i + 1, e);
+        let name = r.name.map(|re| Regex::new(&re)).transpose().map_err(|e| err(e.to_string()))?;
+        let rule = Rule {
+            name,
+            extensions: r.extensions.iter().map(|e| e.trim_start_matches('.').to_lowercase()).collect(),
+            min_size: r.min_size,
+            max_size: r.max_size,
+            min_files: r.min_files,
+            max_files: r.max_files,
+        };
+        if rule.name.is_none() && rule.extensions.is_empty() && rule.min_size.is_none() && rule.max_size.is_none()
+            && rule.min_files.is_none() && rule.max_files.is_none() {
+            return Err(err("no condition, it would drop everything".to_string()));
+        }
+        rules.drop.push(rule);
+    }
+    Ok(rules)
+}
+
+impl Rules {
+    pub fn drops_hash(&self, h: &InfoHash) -> bool {
+        self.info_hashes.contains(h)
+    }
+
+    /// Index of the first rule `t` matches.
+    pub fn drops(&self, t: &Torrent) -> Option<usize> {
+        self.drop.iter().position(|r| r.matches(t))
+    }
+}
+
+/// The rules of a file, reloaded by `reload` when it changes.
+pub struct Filter {
+    path: PathBuf,
+    rules: RwLock<Arc<Rules>>,
+    modified: Mutex<Option<SystemTime>>,
+    /// info hashes of the torrents the rules dropped, forgotten on reload
+    dropped: Mutex<HashSet<InfoHash>>,
+}
+
+/// Loads the rules at `path`.
+pub fn open(path: &str) -> Result<Filter, String> {
+    let f = Filter {
+        path: PathBuf::from(path),
+        rules: RwLock::new(Arc::new(Rules::default())),
+        modified: Mutex::new(None),
+        dropped: Mutex::new(HashSet::new()),
+    };
+    f.reload()?;
+    Ok(f)
+}
+
+impl Filter {
+    /// Rereads the file if it was modified since the last load, true if it
+    /// was. The current rules stay in place if the new ones are invalid.
+    pub fn reload(&self) -> Result<bool, String> {
+        let err = |e: String| format!("{}: {}", self.path.display(), e);
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).map_err(|e| err(e.to_string()))?;
+        if *self.modified.lock().unwrap() == Some(modified) {
+            return Ok(false);
+        }
+        let text = fs::read_to_string(&self.path).map_err(|e| err(e.to_string()))?;
+        let rules = parse(&text);
+        // a broken file is not read again until it changes
+        *self.modified.lock().unwrap() = Some(modified);
+        let rules = rules.map_err(err)?;
+        info!(path = %self.path.display(), info_hashes = rules.info_hashes.len(), rules = rules.drop.len(),
+            "loaded the rules");
+        *self.rules.write().unwrap() = Arc::new(rules);
+        self.dropped.lock().unwrap().clear();
+        Ok(true)
+    }
+
+    /// Whether the announces of `h` are dropped, because of its info hash or
+    /// because its torrent was.
+    pub fn drops_hash(&self, h: &InfoHash) -> bool {
+        self.rules.read().unwrap().drops_hash(h) || self.dropped.lock().unwrap().contains(h)
+    }
+
+    /// Index of the first rule `t` matches, `t` is then remembered until the
+    /// rules change.
+    pub fn drops(&self, t: &Torrent) -> Option<usize> {
+        let rule = self.rules.read().unwrap().drops(t)?;
+        let mut dropped = self.dropped.lock().unwrap();
+        if dropped.len() >= MAX_DROPPED {
+            dropped.clear();
+        }
+        dropped.insert(t.hash);
+        Some(rule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::{torrent, TempDir};
+
+    #[test]
+    fn rules() {
+        let h = InfoHash::random();
+        let r = parse(&format!(r#"
+            info_hashes = ["{}"]
+            [[drop]]
+            name = "(?i)\\bsample\\b"
+            [[drop]]
+            extensions = [".EXE", "scr"]
+            [[drop]]
+            max_size = 1000
+            max_files = 1
+        "#, h)).unwrap();
+        assert!(r.drops_hash(&h));
+        assert!(!r.drops_hash(&InfoHash::random()));
+
+        assert_eq!(r.drops(&torrent("Movie SAMPLE", &[("a.mkv", 5000)])), Some(0));
+        assert_eq!(r.drops(&torrent("Movie", &[("a.mkv", 5000), ("dir/setup.Exe", 10)])), Some(1));
+        assert_eq!(r.drops(&torrent("tiny", &[("a.txt", 10)])), Some(2));
+        assert_eq!(r.drops(&torrent("tiny", &[("a.txt", 10), ("b.txt", 10)])), None);
+        assert_eq!(r.drops(&torrent("Samples", &[("exe", 5000)])), None);
+
+        assert!(parse("[[drop]]\nname = \"(\"").unwrap_err().starts_with("drop #1: "));
+        assert!(parse("[[drop]]\n").is_err());
+        assert!(parse("info_hashes = [\"nope\"]").is_err());
+        assert!(parse("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn reload() {
+        let dir = TempDir::new("rules");
+        let path = dir.join("rules.toml");
+        let (a, b) = (InfoHash::random(), InfoHash::random());
+        fs::write(&path, format!("info_hashes = [\"{}\"]\n[[drop]]\nmax_files = 1\n", a)).unwrap();
+        let f = open(&path.to_string_lossy()).unwrap();
+        assert!(f.drops_hash(&a));
+        assert!(!f.reload().unwrap());
+
+        let t = torrent("one file", &[("a", 1)]);
+        assert_eq!(f.drops(&t), Some(0));
+        assert!(f.drops_hash(&t.hash));
+
+        // the modification time may not change within the same second
+        let set_modified = |secs| {
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)).unwrap();
+        };
+        fs::write(&path, format!("info_hashes = [\"{}\"]\n", b)).unwrap();
+        set_modified(1);
+        assert!(f.reload().unwrap());
+        assert!(!f.drops_hash(&a) && f.drops_hash(&b));
+        assert!(!f.drops_hash(&t.hash));
+
+        // broken rules leave the previous ones
+        fs::write(&path, "info_hashes = [").unwrap();
+        set_modified(2);
+        assert!(f.reload().is_err());
+        assert!(f.drops_hash(&b));
+    }
+}
@@ -0,0 +1,60 @@
+//! Where the fetched info dictionaries are kept.
+//!
+//! Every backend stores the info dictionary exactly as the peer sent it, so
+//! that its SHA-1 still is the info hash.
+
+use super::id::InfoHash;
+
+pub mod db;
+pub mod fs;
+
+pub trait TorrentStore: Send + Sync {
+    /// Saves the info dictionary of `hash`, replacing any previous one.
+    fn put(&self, hash: &InfoHash, info: &[u8]) -> Result<(), String>;
+    fn get(&self, hash: &InfoHash) -> Result<Option<Vec<u8>>, String>;
+    fn exists(&self, hash: &InfoHash) -> Result<bool, String>;
+    /// Every info hash held, in no particular order.
+    fn list(&self) -> Result<Vec<InfoHash>, String>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::TempDir;
+
+    fn check(s: &dyn TorrentStore) {
+        let a = InfoHash::random();
+        let b = InfoHash::random();
+        assert_eq!(s.get(&a), Ok(None));
+        assert_eq!(s.exists(&a), Ok(false));
+        s.put(&a, b"d4:name1:ae").unwrap();
+        s.put(&b, b"d4:name1:be").unwrap();
+        s.put(&b, b"d4:name1:ce").unwrap();
+        assert_eq!(s.exists(&a), Ok(true));
+        assert_eq!(s.get(&a), Ok(Some(b"d4:name1:ae".to_vec())));
+        assert_eq!(s.get(&b), Ok(Some(b"d4:name1:ce".to_vec())));
+        let mut list = s.list().unwrap();
+        list.sort();
+        let mut want = vec![a, b];
+        want.sort();
+        assert_eq!(list, want);
+    }
+
+    #[test]
+    fn fs_store() {
+        let dir = TempDir::new("fs");
+        // torrents outside of the shards aren't the store's
+        let other = InfoHash::random().to_hex();
+        for sub in ["target/debug", "AB/CD", "abc/de"].iter() {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+            std::fs::write(dir.join(sub).join(format!("{}.torrent", other)), b"d4:infod4:name1:aee").unwrap();
+        }
+        check(&fs::FsStore::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn db_store() {
+        let dir = TempDir::new("db");
+        check(&db::DbStore::open(dir.path()).unwrap());
+    }
+}
@@ -0,0 +1,43 @@
+//! Info dictionaries in an embedded sled database, keyed by the raw info hash.
+
+use std::path::Path;
+
+use super::super::id::InfoHash;
+use super::TorrentStore;
+
+pub struct DbStore {
+    db: sled::Db,
+}
+
+impl DbStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DbStore, String> {
+        Ok(DbStore { db: sled::open(path).map_err(|e| e.to_string())? })
+    }
+}
+
+impl TorrentStore for DbStore {
+    fn put(&self, hash: &InfoHash, info: &[u8]) -> Result<(), String> {
+        self.db.insert(hash.as_bytes(), info).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn get(&self, hash: &InfoHash) -> Result<Option<Vec<u8>>, String> {
+        let v = self.db.get(hash.as_bytes()).map_err(|e| e.to_string())?;
+        Ok(v.map(|v| v.to_vec()))
+    }
+
+    fn exists(&self, hash: &InfoHash) -> Result<bool, String> {
+        self.db.contains_key(hash.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn list(&self) -> Result<Vec<InfoHash>, String> {
+        let mut hashes = vec![];
+        for k in self.db.iter().keys() {
+            let k = k.map_err(|e| e.to_string())?;
+            if let Some(hash) = InfoHash::from_bytes(&k) {
+                hashes.push(hash);
+            }
+        }
+        Ok(hashes)
+    }
+}
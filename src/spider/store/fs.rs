@@ -0,0 +1,104 @@
+//! One `.torrent` file per info hash, in `<dir>/ab/cd/abcd….torrent` so that
+//! no directory grows too large.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::super::id::InfoHash;
+use super::TorrentStore;
+
+const PREFIX: &[u8] = b"d4:info";
+const SUFFIX: &[u8] = b"e";
+
+pub struct FsStore {
+    dir: PathBuf,
+}
+
+impl FsStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FsStore, io::Error> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(FsStore { dir: dir.as_ref().to_path_buf() })
+    }
+
+    fn shard(&self, hex: &str) -> PathBuf {
+        self.dir.join(&hex[..2]).join(&hex[2..4])
+    }
+
+    fn path(&self, hash: &InfoHash) -> PathBuf {
+        let hex = hash.to_hex();
+        self.shard(&hex).join(format!("{}.torrent", hex))
+    }
+}
+
+impl TorrentStore for FsStore {
+    /// Writes a torrent file holding only the info dictionary. It is written
+    /// to a temporary file first and renamed, so readers never see half of it.
+    fn put(&self, hash: &InfoHash, info: &[u8]) -> Result<(), String> {
+        let hex = hash.to_hex();
+        let dir = self.shard(&hex);
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let tmp = dir.join(format!(".{}.tmp", hex));
+        let write = || -> io::Result<()> {
+            let mut f = fs::File::create(&tmp)?;
+            f.write_all(PREFIX)?;
+            f.write_all(info)?;
+            f.write_all(SUFFIX)?;
+            f.sync_all()?;
+            fs::rename(&tmp, self.path(hash))
+        };
+        write().map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            e.to_string()
+        })
+    }
+
+    fn get(&self, hash: &InfoHash) -> Result<Option<Vec<u8>>, String> {
+        let dat = match fs::read(self.path(hash)) {
+            Ok(dat) => dat,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+        if !dat.starts_with(PREFIX) || !dat.ends_with(SUFFIX) || dat.len() < PREFIX.len() + SUFFIX.len() {
+            return Err(format!("{}: not written by this store", self.path(hash).display()));
+        }
+        Ok(Some(dat[PREFIX.len()..dat.len() - SUFFIX.len()].to_vec()))
+    }
+
+    fn exists(&self, hash: &InfoHash) -> Result<bool, String> {
+        Ok(self.path(hash).is_file())
+    }
+
+    fn list(&self) -> Result<Vec<InfoHash>, String> {
+        let mut hashes = vec![];
+        for a in shards(&self.dir)? {
+            for b in shards(&a)? {
+                for f in fs::read_dir(&b).map_err(|e| e.to_string())? {
+                    let name = f.map_err(|e| e.to_string())?.file_name();
+                    let name = name.to_string_lossy();
+                    if let Some(hash) = name.strip_suffix(".torrent").and_then(InfoHash::from_hex) {
+                        hashes.push(hash);
+                    }
+                }
+            }
+        }
+        Ok(hashes)
+    }
+}
+
+/// Sub directories of `dir` named like a shard, two lowercase hex digits.
+/// Anything else may share the directory, the default one is the working
+/// directory.
+fn shards(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut dirs = vec![];
+    for e in fs::read_dir(dir).map_err(|e| e.to_string())? {
+        let p = e.map_err(|e| e.to_string())?.path();
+        let is_shard = p.file_name().and_then(|n| n.to_str())
+            .is_some_and(|n| n.len() == 2 && n.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')));
+        if is_shard && p.is_dir() {
+            dirs.push(p);
+        }
+    }
+    Ok(dirs)
+}
@@ -0,0 +1,44 @@
+//! Fixtures shared by the tests.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::id::InfoHash;
+use super::wire::{File, Torrent};
+
+/// A new directory under the system temp directory, removed with everything
+/// in it when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let dir = env::temp_dir().join(format!("p2pspider-{}-{}", name, InfoHash::random()));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A torrent of `files`, given as path and length, under a random info hash.
+pub fn torrent(name: &str, files: &[(&str, i64)]) -> Torrent {
+    Torrent {
+        hash: InfoHash::random(),
+        name: name.to_string(),
+        length: files.iter().map(|f| f.1).sum(),
+        files: files.iter().map(|&(name, length)| File { name: name.to_string(), length }).collect(),
+    }
+}
@@ -0,0 +1,619 @@
+extern crate bencode;
+extern crate byteorder;
+extern crate sha1;
+
+use self::bencode::Bencode;
+use self::bencode::DictMap;
+use self::bencode::util::ByteString;
+use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net;
+use std::net::ToSocketAddrs;
+use std::time;
+use tracing::{debug, instrument, trace};
+
+use super::id::InfoHash;
+
+mod error;
+pub mod seeder;
+
+pub use self::error::WireError;
+
+const PER_BLOCK: i32 = 16384;
+pub const MAX_META_DATA_SIZE: i32 = PER_BLOCK * 1024;
+/// whole fetch, from connecting to the last piece
+pub const DEFAULT_TIMEOUT_SEC: i32 = 30;
+pub const DEFAULT_CONNECT_TIMEOUT_SEC: i32 = 5;
+/// both handshakes, until the peer told us the metadata size
+pub const DEFAULT_HANDSHAKE_TIMEOUT_SEC: i32 = 5;
+const EXTENDED: u8 = 20;
+const EXT_HANDSHAKE: u8 = 0;
+/// id the peer uses for the ut_metadata messages it sends us
+const UT_METADATA: u8 = 1;
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+/// a piece and its header, or a bitfield of a very large torrent
+const MAX_MESSAGE_LEN: u32 = 1 << 20;
+/// lists and dictionaries nested deeper are rejected: the bencode crate
+/// parses them recursively, and a peer could overflow the stack
+pub const MAX_DEPTH: usize = 64;
+
+
+
+fn random_peer_id() -> Vec<u8> {
+    super::dht::rand_bytes(20)
+}
+
+/// Downloads the info dictionary of a torrent from one peer with the extension
+/// protocol (BEP 10) and ut_metadata (BEP 9).
+pub struct Wire {
+    info_hash: InfoHash,
+    peer_id: Vec<u8>,
+    peer: net::SocketAddr,
+    conn: net::TcpStream,
+    started: time::Instant,
+    timeout_sec: i32,
+    handshake_timeout_sec: i32,
+    max_metadata_size: i32,
+    metadata_size: i32,
+    /// id the peer wants for the ut_metadata messages we send
+    ut_metadata: u8,
+    pieces: Vec<Vec<u8>>,
+}
+
+/// Connects to `from`, giving up after `timeout_sec`. The whole fetch timeout
+/// counts from here.
+pub fn connect(info: InfoHash, from: String, timeout_sec: i32) -> Result<Wire, WireError> {
+    let started = time::Instant::now();
+    let addr = from.as_str().to_socket_addrs().map_err(WireError::Connect)?
+        .next()
+        .ok_or_else(|| WireError::Connect(io::Error::new(io::ErrorKind::InvalidInput, "no address")))?;
+    let timeout = time::Duration::from_secs(timeout_sec.max(1) as u64);
+    let stream = net::TcpStream::connect_timeout(&addr, timeout).map_err(|e| match e.kind() {
+        io::ErrorKind::TimedOut => WireError::Timeout,
+        _ => WireError::Connect(e),
+    })?;
+    Ok(Wire {
+        info_hash: info,
+        peer_id: random_peer_id(),
+        peer: addr,
+        conn: stream,
+        started,
+        timeout_sec: DEFAULT_TIMEOUT_SEC,
+        handshake_timeout_sec: DEFAULT_HANDSHAKE_TIMEOUT_SEC,
+        max_metadata_size: MAX_META_DATA_SIZE,
+        metadata_size: 0,
+        ut_metadata: 0,
+        pieces: Vec::new(),
+    })
+}
+
+impl Wire {
+    pub fn timeout_sec(mut self, n: i32) -> Wire {
+        self.timeout_sec = n;
+        self
+    }
+    pub fn handshake_timeout_sec(mut self, n: i32) -> Wire {
+        self.handshake_timeout_sec = n;
+        self
+    }
+    pub fn max_metadata_size(mut self, n: i32) -> Wire {
+        self.max_metadata_size = n;
+        self
+    }
+
+    /// Limits the next socket operations to what is left of the handshake
+    /// timeout until the metadata size is known, then of the whole timeout.
+    fn set_deadline(&self) -> Result<(), WireError> {
+        let mut limit = time::Duration::from_secs(self.timeout_sec as u64);
+        if self.metadata_size == 0 {
+            limit = limit.min(time::Duration::from_secs(self.handshake_timeout_sec as u64));
+        }
+        let left = match limit.checked_sub(self.started.elapsed()) {
+            Some(left) if left > time::Duration::from_millis(0) => left,
+            _ => return Err(WireError::Timeout),
+        };
+        self.conn.set_read_timeout(Some(left))?;
+        self.conn.set_write_timeout(Some(left))?;
+        Ok(())
+    }
+
+    /// Downloads and verifies the info dictionary.
+    #[instrument(name = "wire", level = "debug", skip(self), fields(peer = %self.peer, info_hash = %self.info_hash),
+                 err(level = "debug"))]
+    pub fn fetch(&mut self) -> Result<Vec<u8>, WireError> {
+        self.set_deadline()?;
+        let mut h = handshake_header();
+        h.extend_from_slice(self.info_hash.as_bytes());
+        h.extend_from_slice(&self.peer_id);
+        self.conn.write_all(&h)?;
+        self.on_handshake()?;
+        self.ext_handshake()?;
+        loop {
+            self.set_deadline()?;
+            let data = self.next()?;
+            if data.len() < 2 || data[0] != EXTENDED {
+                continue;
+            }
+            self.on_extended(data[1], &data[2..])?;
+            if !self.is_done() {
+                continue;
+            }
+            let m = self.pieces.concat();
+            if sha1::Sha1::from(&m).digest().bytes() == *self.info_hash.as_bytes() {
+                debug!(millis = self.started.elapsed().as_millis() as u64, "metadata verified");
+                return Ok(m);
+            }
+            return Err(WireError::ChecksumMismatch);
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.metadata_size > 0 && self.pieces.iter().all(|p| !p.is_empty())
+    }
+
+    fn on_handshake(&mut self) -> Result<(), WireError> {
+        let mut buf = [0; 68];
+        self.conn.read_exact(&mut buf[..])?;
+        let header = handshake_header();
+        if buf[..20] != header[..20] {
+            return Err(WireError::NotBittorrent);
+        }
+        if buf[25] & 0x10 != 0x10 {
+            return Err(WireError::NoExtensionProtocol);
+        }
+        if buf[28..48] != self.info_hash.as_bytes()[..] {
+            return Err(WireError::InfoHashMismatch);
+        }
+        Ok(())
+    }
+
+    fn ext_handshake(&mut self) -> Result<(), WireError> {
+        let mut m = DictMap::new();
+        let mut m_inner = DictMap::new();
+        m_inner.insert(ByteString::from_str("ut_metadata"), Bencode::Number(UT_METADATA as i64));
+        m.insert(ByteString::from_str("m"), Bencode::Dict(m_inner));
+        write_extended(&mut self.conn, EXT_HANDSHAKE, &Bencode::Dict(m), &[])
+    }
+
+    fn next(&mut self) -> Result<Vec<u8>, WireError> {
+        read_message(&mut self.conn)
+    }
+
+    fn on_extended(&mut self, ext: u8, payload: &[u8]) -> Result<(), WireError> {
+        match ext {
+            EXT_HANDSHAKE => self.on_ext_handshake(payload),
+            UT_METADATA => self.on_ut_metadata(payload),
+            _ => Ok(()),
+        }
+    }
+
+    fn on_ut_metadata(&mut self, payload: &[u8]) -> Result<(), WireError> {
+        if self.metadata_size == 0 {
+            return Err(WireError::Protocol("ut_metadata message before the extension handshake"));
+        }
+        let (m, data) = split_header(payload).ok_or(WireError::InvalidPiece)?;
+        let piece = match m.get(&ByteString::from_str("piece")) {
+            Some(Bencode::Number(i)) if *i >= 0 && (*i as usize) < self.pieces.len() => *i as usize,
+            _ => return Err(WireError::InvalidPiece),
+        };
+        match m.get(&ByteString::from_str("msg_type")) {
+            Some(Bencode::Number(MSG_DATA)) => {}
+            Some(Bencode::Number(MSG_REJECT)) => return Err(WireError::Rejected(piece)),
+            Some(Bencode::Number(MSG_REQUEST)) => {
+                // we are leeching, we have nothing to give
+                let mut r = DictMap::new();
+                r.insert(ByteString::from_str("msg_type"), Bencode::Number(MSG_REJECT));
+                r.insert(ByteString::from_str("piece"), Bencode::Number(piece as i64));
+                return write_extended(&mut self.conn, self.ut_metadata, &Bencode::Dict(r), &[]);
+            }
+            _ => return Err(WireError::InvalidPiece),
+        }
+        match m.get(&ByteString::from_str("total_size")) {
+            Some(Bencode::Number(n)) if *n == self.metadata_size as i64 => {}
+            None => {}
+            _ => return Err(WireError::Protocol("total_size does not match metadata_size")),
+        }
+        if data.len() != piece_len(self.metadata_size, piece) {
+            return Err(WireError::InvalidPiece);
+        }
+        trace!(piece, "piece received");
+        self.pieces[piece] = data.to_vec();
+        Ok(())
+    }
+
+    fn request_piece(&mut self, i: usize) -> Result<(), WireError> {
+        let mut m = DictMap::new();
+        m.insert(ByteString::from_str("msg_type"), Bencode::Number(MSG_REQUEST));
+        m.insert(ByteString::from_str("piece"), Bencode::Number(i as i64));
+        write_extended(&mut self.conn, self.ut_metadata, &Bencode::Dict(m), &[])
+    }
+
+    fn on_ext_handshake(&mut self, payload: &[u8]) -> Result<(), WireError> {
+        if self.metadata_size > 0 {
+            // peers may send it again to update their settings
+            return Ok(());
+        }
+        let m = match decode(payload) {
+            Some(Bencode::Dict(m)) => m,
+            _ => return Err(WireError::ExtHeader),
+        };
+        let meta_size = match m.get(&ByteString::from_str("metadata_size")) {
+            Some(Bencode::Number(size)) if *size > 0 => *size,
+            _ => return Err(WireError::ExtHeader),
+        };
+        if meta_size > self.max_metadata_size as i64 {
+            return Err(WireError::MetadataTooLarge(meta_size));
+        }
+        let ut_meta = match m.get(&ByteString::from_str("m")) {
+            Some(Bencode::Dict(inner_m)) => match inner_m.get(&ByteString::from_str("ut_metadata")) {
+                Some(Bencode::Number(u)) if *u > 0 && *u < 256 => *u as u8,
+                _ => return Err(WireError::NoUtMetadata),
+            },
+            _ => return Err(WireError::ExtHeader),
+        };
+        self.metadata_size = meta_size as i32;
+        self.ut_metadata = ut_meta;
+        let num_of_pieces = ((meta_size + PER_BLOCK as i64 - 1) / PER_BLOCK as i64) as usize;
+        debug!(metadata_size = meta_size, pieces = num_of_pieces, "extension handshake");
+        self.pieces = vec![vec![]; num_of_pieces];
+        for i in 0..num_of_pieces {
+            self.request_piece(i)?;
+        }
+        Ok(())
+    }
+}
+
+/// Protocol name and reserved bytes of the handshake, with the extension
+/// protocol bit set.
+fn handshake_header() -> Vec<u8> {
+    let mut r = b"\x13BitTorrent protocol".to_vec();
+    r.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x01]);
+    r
+}
+
+/// Writes one length prefixed extended message.
+fn write_extended<W: Write>(conn: &mut W, ext: u8, header: &Bencode, payload: &[u8]) -> Result<(), WireError> {
+    let header = header.to_bytes()?;
+    let mut v = Vec::with_capacity(6 + header.len() + payload.len());
+    v.write_u32::<BigEndian>((2 + header.len() + payload.len()) as u32)?;
+    v.push(EXTENDED);
+    v.push(ext);
+    v.extend_from_slice(&header);
+    v.extend_from_slice(payload);
+    conn.write_all(&v)?;
+    Ok(())
+}
+
+/// Reads the next message, skipping keep-alives.
+fn read_message<R: Read>(conn: &mut R) -> Result<Vec<u8>, WireError> {
+    loop {
+        let size = conn.read_u32::<BigEndian>()?;
+        if size == 0 {
+            continue;
+        }
+        if size > MAX_MESSAGE_LEN {
+            return Err(WireError::MessageTooLong(size));
+        }
+        let mut data = vec![0; size as usize];
+        conn.read_exact(&mut data)?;
+        return Ok(data);
+    }
+}
+
+/// Size of piece `i` of metadata of `size` bytes.
+fn piece_len(size: i32, i: usize) -> usize {
+    let size = size as usize;
+    let block = PER_BLOCK as usize;
+    if (i + 1) * block <= size {
+        block
+    } else {
+        size - i * block
+    }
+}
+
+/// Splits a ut_metadata payload into its bencoded dictionary and the piece
+/// data appended to it.
+fn split_header(payload: &[u8]) -> Option<(DictMap, &[u8])> {
+    let n = bencoded_len(payload)?;
+    match bencode::from_buffer(&payload[..n]) {
+        Ok(Bencode::Dict(m)) => Some((m, &payload[n..])),
+        _ => None,
+    }
+}
+
+/// Length of the bencoded value at the start of `b`, `None` if it is invalid,
+/// truncated or nested deeper than `MAX_DEPTH`.
+pub fn bencoded_len(b: &[u8]) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    loop {
+        match *b.get(i)? {
+            b'i' => i += b[i..].iter().position(|&c| c == b'e')? + 1,
+            b'l' | b'd' => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return None;
+                }
+                i += 1;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                i += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = i + b[i..].iter().position(|&c| c == b':')?;
+                let len: usize = std::str::from_utf8(&b[i..colon]).ok()?.parse().ok()?;
+                let end = colon.checked_add(1)?.checked_add(len)?;
+                if end > b.len() {
+                    return None;
+                }
+                i = end;
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(i);
+        }
+    }
+}
+
+/// Decodes bencode received from a peer, which must be a single value.
+fn decode(b: &[u8]) -> Option<Bencode> {
+    if bencoded_len(b)? != b.len() {
+        return None;
+    }
+    bencode::from_buffer(b).ok()
+}
+
+pub fn parse_data(meta: Vec<u8>, hash: InfoHash) -> Result<Torrent, WireError> {
+    let ben = decode(&meta).ok_or(WireError::InvalidInfo("not bencoded"))?;
+    let mut torrent = Torrent { hash, name: String::new(), length: 0, files: Vec::new() };
+    if let Bencode::Dict(dict) = ben {
+        let name = |key| match dict.get(&ByteString::from_str(key)) {
+            Some(Bencode::ByteString(d)) => Some(d),
+            _ => None,
+        };
+        // `name` is in whatever encoding the author used, keep what can be read
+        torrent.name = match name("name.utf-8").and_then(|d| std::str::from_utf8(d).ok()) {
+            Some(s) => s.to_string(),
+            None => name("name").or_else(|| name("name.utf-8"))
+                .map(|d| String::from_utf8_lossy(d).into_owned())
+                .unwrap_or_default(),
+        };
+        if let Some(Bencode::Number(l)) = dict.get(&ByteString::from_str("length")) {
+            torrent.length = *l;
+        }
+        let mut total: i64 = 0;
+        if let Some(Bencode::List(list)) = dict.get(&ByteString::from_str("files")) {
+            for f in list {
+                // todo
+                if let Bencode::Dict(f_dict) = f {
+                    let mut fullname = String::new();
+                    let mut size: i64 = 0;
+                    let mut path: Vec<Bencode> = vec![];
+                    if let Some(Bencode::List(inter)) = f_dict.get(&ByteString::from_str("path.utf-8")) {
+                        path = inter.to_vec();
+                    } else if let Some(Bencode::List(inter)) = f_dict.get(&ByteString::from_str("path")) {
+                        path = inter.to_vec();
+                    }
+                    let mut first = true;
+                    for p in path {
+                        let p = match p {
+                            Bencode::ByteString(ref b) => String::from_utf8_lossy(b).into_owned(),
+                            _ => continue,
+                        };
+                        if first {
+                            fullname.push_str(&p);
+                            first = false;
+                        } else {
+                            fullname.push('/');
+                            fullname.push_str(&p);
+                        }
+                    }
+                    if let Some(Bencode::Number(ref i)) = f_dict.get(&ByteString::from_str("length")) {
+                        total += *i;
+                        size = *i;
+                    }
+                    torrent.files.push(File { name: fullname, length: size });
+                }
+            }
+        }
+
+        if torrent.length == 0 {
+            torrent.length = total;
+        }
+        if torrent.files.is_empty() {
+            torrent.files.push(File { name: torrent.name.clone(), length: torrent.length });
+        }
+        return Ok(torrent);
+    }
+    Err(WireError::InvalidInfo("not a dictionary"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Torrent {
+    pub hash: InfoHash,
+    pub name: String,
+    /// total size of the files
+    pub length: i64,
+    pub files: Vec<File>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct File {
+    /// path inside the torrent, `/` separated
+    pub name: String,
+    pub length: i64,
+}
+
+impl fmt::Display for Torrent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f,"link: magnet:?xt=urn:btih:{}\nname {}\nsize: {}\nfile: {}\n", self.hash, self.name, self.length, self.files.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// What the scripted peer answers to the piece requests.
+    enum Script {
+        Send,
+        Reject,
+        WrongTotalSize,
+    }
+
+    fn dict(items: &[(&str, Bencode)]) -> Bencode {
+        Bencode::Dict(items.iter().map(|(k, v)| (ByteString::from_str(k), v.clone())).collect())
+    }
+
+    /// Writes an extended message, the client may have hung up already.
+    fn write_msg(conn: &mut net::TcpStream, ext: u8, header: &Bencode, payload: &[u8]) {
+        let _ = write_extended(conn, ext, header, payload);
+    }
+
+    fn read_msg(conn: &mut net::TcpStream) -> Option<Vec<u8>> {
+        read_message(conn).ok()
+    }
+
+    /// Starts a peer serving `metadata` for `hash`, advertising `size` as
+    /// metadata_size, and fetches from it.
+    fn fetch_from(metadata: Vec<u8>, hash: InfoHash, size: usize, script: Script) -> Result<Vec<u8>, WireError> {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut h = [0; 68];
+            conn.read_exact(&mut h).unwrap();
+            assert_eq!(h[..20], handshake_header()[..20]);
+            let mut reply = handshake_header();
+            reply.extend_from_slice(&h[28..48]);
+            reply.extend_from_slice(&[b'x'; 20]);
+            conn.write_all(&reply).unwrap();
+
+            // a keep-alive and an unrelated message first
+            conn.write_all(&[0, 0, 0, 0, 0, 0, 0, 1, 2]).unwrap();
+            let ext = read_msg(&mut conn).unwrap();
+            assert_eq!(ext[..2], [EXTENDED, EXT_HANDSHAKE]);
+            let hs = dict(&[
+                ("m", dict(&[("ut_metadata", Bencode::Number(3))])),
+                ("metadata_size", Bencode::Number(size as i64)),
+            ]);
+            write_msg(&mut conn, EXT_HANDSHAKE, &hs, &[]);
+
+            for _ in 0..size.div_ceil(PER_BLOCK as usize) {
+                let req = match read_msg(&mut conn) {
+                    Some(req) => req,
+                    None => return,
+                };
+                assert_eq!(req[..2], [EXTENDED, 3]);
+                let m = match bencode::from_buffer(&req[2..]).unwrap() {
+                    Bencode::Dict(m) => m,
+                    _ => panic!("request is not a dict"),
+                };
+                let i = match m.get(&ByteString::from_str("piece")) {
+                    Some(Bencode::Number(i)) => *i as usize,
+                    _ => panic!("request without piece"),
+                };
+                let start = i * PER_BLOCK as usize;
+                let end = metadata.len().min(start + PER_BLOCK as usize);
+                let (msg_type, total) = match script {
+                    Script::Send => (MSG_DATA, size),
+                    Script::Reject => (MSG_REJECT, size),
+                    Script::WrongTotalSize => (MSG_DATA, size + 1),
+                };
+                let header = dict(&[
+                    ("msg_type", Bencode::Number(msg_type)),
+                    ("piece", Bencode::Number(i as i64)),
+                    ("total_size", Bencode::Number(total as i64)),
+                ]);
+                let data = if msg_type == MSG_DATA { &metadata[start..end] } else { &[][..] };
+                write_msg(&mut conn, UT_METADATA, &header, data);
+            }
+        });
+        let result = connect(hash, addr.to_string(), 1)
+            .unwrap()
+            .max_metadata_size(1 << 20)
+            .fetch();
+        peer.join().unwrap();
+        result
+    }
+
+    fn metadata() -> (Vec<u8>, InfoHash) {
+        let mut info = b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces".to_vec();
+        let filler = vec![b'p'; 2 * PER_BLOCK as usize];
+        info.extend_from_slice(format!("{}:", filler.len()).as_bytes());
+        info.extend_from_slice(&filler);
+        info.push(b'e');
+        let hash = InfoHash::from(sha1::Sha1::from(&info).digest().bytes());
+        (info, hash)
+    }
+
+    #[test]
+    fn fetch_pieces() {
+        let (info, hash) = metadata();
+        assert_eq!(fetch_from(info.clone(), hash, info.len(), Script::Send).unwrap(), info);
+    }
+
+    #[test]
+    fn fetch_errors() {
+        let (info, hash) = metadata();
+        let n = info.len();
+        let e = fetch_from(info.clone(), hash, n, Script::Reject).unwrap_err();
+        assert!(matches!(e, WireError::Rejected(0)));
+        assert!(e.is_retryable());
+        assert!(matches!(fetch_from(info.clone(), hash, n, Script::WrongTotalSize), Err(WireError::Protocol(_))));
+        // the peer echoes whatever hash we ask for
+        let e = fetch_from(info.clone(), InfoHash::default(), n, Script::Send).unwrap_err();
+        assert!(matches!(e, WireError::ChecksumMismatch));
+        assert!(e.is_misbehaving() && !e.is_retryable());
+        assert!(matches!(fetch_from(info, hash, 2 << 20, Script::Send), Err(WireError::MetadataTooLarge(n)) if n == 2 << 20));
+    }
+
+    #[test]
+    fn names() {
+        let name = |info: &[u8]| parse_data(info.to_vec(), InfoHash::default()).unwrap().name;
+        assert_eq!(name(b"d6:lengthi1e4:name3:abce"), "abc");
+        // Latin-1
+        assert_eq!(name(b"d6:lengthi1e4:name4:caf\xe9e"), "caf\u{fffd}");
+        assert_eq!(name(b"d6:lengthi1e4:name4:caf\xe910:name.utf-85:caf\xc3\xa9e"), "caf\u{e9}");
+        assert_eq!(name(b"d6:lengthi1e4:name3:abc10:name.utf-84:caf\xe9e"), "abc");
+    }
+
+    #[test]
+    fn error_kinds() {
+        let closed = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let e = connect(InfoHash::default(), closed.to_string(), 1).err().unwrap();
+        assert_eq!(e.kind(), "connect");
+        assert!(e.is_retryable() && !e.is_misbehaving());
+        assert!(matches!(WireError::from(io::Error::from(io::ErrorKind::WouldBlock)), WireError::Timeout));
+        assert_eq!(WireError::from(io::Error::from(io::ErrorKind::ConnectionReset)).kind(), "io");
+        assert!(matches!(parse_data(b"i3e".to_vec(), InfoHash::default()), Err(WireError::InvalidInfo(_))));
+    }
+
+    #[test]
+    fn bencoded_values() {
+        assert_eq!(bencoded_len(b"d8:msg_typei1e5:piecei0eexyz"), Some(25));
+        assert_eq!(bencoded_len(b"l4:spami-3ee"), Some(12));
+        assert_eq!(bencoded_len(b"d3:abc"), None);
+        assert_eq!(bencoded_len(b"9:ab"), None);
+        assert_eq!(bencoded_len(b"e"), None);
+        assert_eq!(bencoded_len(b"lle"), None);
+
+        let nested = |n| [vec![b'l'; n], vec![b'e'; n]].concat();
+        assert_eq!(bencoded_len(&nested(MAX_DEPTH)), Some(2 * MAX_DEPTH));
+        assert_eq!(bencoded_len(&nested(MAX_DEPTH + 1)), None);
+        assert!(decode(&nested(MAX_DEPTH + 1)).is_none());
+        assert!(decode(b"i1ei2e").is_none());
+        // a whole message of it used to overflow the stack
+        let deep = vec![b'l'; MAX_MESSAGE_LEN as usize];
+        assert!(split_header(&deep).is_none());
+    }
+}
@@ -0,0 +1,116 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Why a metadata exchange with a peer failed.
+#[derive(Debug)]
+pub enum WireError {
+    /// the peer couldn't be reached
+    Connect(io::Error),
+    /// the peer was too slow for the handshake or the whole fetch
+    Timeout,
+    /// the connection broke after it was established
+    Io(io::Error),
+    /// the handshake is not a BitTorrent one
+    NotBittorrent,
+    /// the peer doesn't speak the extension protocol (BEP 10)
+    NoExtensionProtocol,
+    /// the peer doesn't speak ut_metadata (BEP 9)
+    NoUtMetadata,
+    /// the peer answered the handshake for another torrent
+    InfoHashMismatch,
+    /// the extension handshake can't be decoded or lacks `metadata_size`
+    ExtHeader,
+    /// the peer claims more metadata than we accept
+    MetadataTooLarge(i64),
+    /// the peer doesn't have piece `n` of the metadata
+    Rejected(usize),
+    /// a ut_metadata message can't be decoded or doesn't fit the metadata
+    InvalidPiece,
+    /// a message longer than any peer should send
+    MessageTooLong(u32),
+    /// a message that breaks the protocol, e.g. ut_metadata before the
+    /// extension handshake
+    Protocol(&'static str),
+    /// the metadata doesn't hash to the info hash
+    ChecksumMismatch,
+    /// the metadata hashes right but is not a usable info dictionary
+    InvalidInfo(&'static str),
+    /// a peer asked the seeder for a torrent we don't hold
+    UnknownInfoHash,
+}
+
+impl WireError {
+    /// Short name for metrics and logs.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            WireError::Connect(_) => "connect",
+            WireError::Timeout => "timeout",
+            WireError::Io(_) => "io",
+            WireError::NotBittorrent | WireError::NoExtensionProtocol | WireError::NoUtMetadata => "unsupported",
+            WireError::InfoHashMismatch | WireError::MessageTooLong(_) | WireError::Protocol(_) => "protocol",
+            WireError::ExtHeader => "ext_header",
+            WireError::MetadataTooLarge(_) => "too_large",
+            WireError::Rejected(_) => "rejected",
+            WireError::InvalidPiece => "invalid_piece",
+            WireError::ChecksumMismatch => "checksum",
+            WireError::InvalidInfo(_) => "invalid_info",
+            WireError::UnknownInfoHash => "unknown_info_hash",
+        }
+    }
+
+    /// Whether asking the same peer again later may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(*self, WireError::Connect(_) | WireError::Timeout | WireError::Io(_) | WireError::Rejected(_))
+    }
+
+    /// Whether the peer breaks the protocol or serves bad data, so that it is
+    /// worth blocking rather than just skipping.
+    pub fn is_misbehaving(&self) -> bool {
+        matches!(*self, WireError::NotBittorrent | WireError::InfoHashMismatch | WireError::ExtHeader
+            | WireError::InvalidPiece | WireError::MessageTooLong(_) | WireError::Protocol(_)
+            | WireError::ChecksumMismatch | WireError::InvalidInfo(_))
+    }
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WireError::Connect(ref e) => write!(f, "couldn't connect: {}", e),
+            WireError::Timeout => f.write_str("timed out"),
+            WireError::Io(ref e) => e.fmt(f),
+            WireError::NotBittorrent => f.write_str("remote peer not supporting bittorrent protocol"),
+            WireError::NoExtensionProtocol => f.write_str("remote peer not supporting extention protocol"),
+            WireError::NoUtMetadata => f.write_str("remote peer not supporting ut_metadata"),
+            WireError::InfoHashMismatch => f.write_str("invalid bittorrent header response"),
+            WireError::ExtHeader => f.write_str("invalid extention header response"),
+            WireError::MetadataTooLarge(n) => write!(f, "metadata_size {} too long", n),
+            WireError::Rejected(piece) => write!(f, "peer rejected piece {}", piece),
+            WireError::InvalidPiece => f.write_str("invalid piece response"),
+            WireError::MessageTooLong(n) => write!(f, "message of {} bytes is too long", n),
+            WireError::Protocol(reason) => f.write_str(reason),
+            WireError::ChecksumMismatch => f.write_str("metadata checksum mismatch"),
+            WireError::InvalidInfo(reason) => write!(f, "invalid info dictionary: {}", reason),
+            WireError::UnknownInfoHash => f.write_str("unknown info hash"),
+        }
+    }
+}
+
+impl Error for WireError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            WireError::Connect(ref e) | WireError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Read and write errors, telling timeouts apart.
+impl From<io::Error> for WireError {
+    fn from(e: io::Error) -> WireError {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => WireError::Timeout,
+            _ => WireError::Io(e),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Serves the info dictionaries we hold to other peers with ut_metadata
+//! (BEP 9), so that they can resolve magnet links against us.
+//!
+//! Peers asking for an info hash we don't have are disconnected right after
+//! their handshake. We never have pieces, so nothing but metadata is served.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::field::{display, Empty};
+use tracing::{debug, debug_span, Span};
+
+use super::bencode::{Bencode, DictMap};
+use super::bencode::util::ByteString;
+use super::super::id::InfoHash;
+use super::{decode, handshake_header, piece_len, read_message, split_header, write_extended};
+use super::{EXTENDED, EXT_HANDSHAKE, MSG_DATA, MSG_REJECT, MSG_REQUEST, PER_BLOCK, UT_METADATA};
+use super::WireError;
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+/// a peer that stays quiet this long is disconnected
+const IDLE_TIMEOUT_SEC: u64 = 30;
+
+pub struct Seeder {
+    listener: net::TcpListener,
+    peer_id: Vec<u8>,
+    max_connections: usize,
+}
+
+pub fn bind(addr: &str) -> Result<Seeder, io::Error> {
+    Ok(Seeder {
+        listener: net::TcpListener::bind(addr)?,
+        peer_id: super::random_peer_id(),
+        max_connections: DEFAULT_MAX_CONNECTIONS,
+    })
+}
+
+impl Seeder {
+    pub fn max_connections(mut self, n: usize) -> Seeder {
+        self.max_connections = n;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts peers on a background thread, one thread per peer. `lookup`
+    /// returns the info dictionary of an info hash, if we hold it.
+    pub fn start<F>(self, lookup: F) -> thread::JoinHandle<()>
+        where F: Fn(&InfoHash) -> Option<Vec<u8>> + Send + Sync + 'static
+    {
+        let lookup = Arc::new(lookup);
+        let active = Arc::new(AtomicUsize::new(0));
+        thread::spawn(move || {
+            for conn in self.listener.incoming() {
+                let conn = match conn {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        debug!(error = %e, "couldn't accept");
+                        continue;
+                    }
+                };
+                let span = match conn.peer_addr() {
+                    Ok(peer) => debug_span!("seed", %peer, info_hash = Empty),
+                    Err(_) => debug_span!("seed", info_hash = Empty),
+                };
+                if active.load(Ordering::Relaxed) >= self.max_connections {
+                    span.in_scope(|| debug!("too many connections, hanging up"));
+                    continue;
+                }
+                active.fetch_add(1, Ordering::Relaxed);
+                let lookup = lookup.clone();
+                let active = active.clone();
+                let peer_id = self.peer_id.clone();
+                thread::spawn(move || {
+                    let _enter = span.enter();
+                    match serve(conn, &peer_id, &*lookup) {
+                        Ok(()) => debug!("session ended"),
+                        Err(e) => debug!(error = %e, "session ended"),
+                    }
+                    active.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+        })
+    }
+}
+
+fn serve<F>(mut conn: net::TcpStream, peer_id: &[u8], lookup: &F) -> Result<(), WireError>
+    where F: Fn(&InfoHash) -> Option<Vec<u8>>
+{
+    let timeout = Some(Duration::from_secs(IDLE_TIMEOUT_SEC));
+    conn.set_read_timeout(timeout)?;
+    conn.set_write_timeout(timeout)?;
+
+    let mut buf = [0; 68];
+    conn.read_exact(&mut buf)?;
+    let header = handshake_header();
+    if buf[..20] != header[..20] {
+        return Err(WireError::NotBittorrent);
+    }
+    if buf[25] & 0x10 != 0x10 {
+        return Err(WireError::NoExtensionProtocol);
+    }
+    let info_hash = InfoHash::from_bytes(&buf[28..48]).ok_or(WireError::InfoHashMismatch)?;
+    Span::current().record("info_hash", display(info_hash));
+    let metadata = match lookup(&info_hash) {
+        // don't serve what the peer would throw away
+        Some(m) if sha1::Sha1::from(&m).digest().bytes() == *info_hash.as_bytes() => m,
+        _ => return Err(WireError::UnknownInfoHash),
+    };
+
+    let mut h = header;
+    h.extend_from_slice(info_hash.as_bytes());
+    h.extend_from_slice(peer_id);
+    conn.write_all(&h)?;
+    let mut m = DictMap::new();
+    let mut m_inner = DictMap::new();
+    m_inner.insert(ByteString::from_str("ut_metadata"), Bencode::Number(UT_METADATA as i64));
+    m.insert(ByteString::from_str("m"), Bencode::Dict(m_inner));
+    m.insert(ByteString::from_str("metadata_size"), Bencode::Number(metadata.len() as i64));
+    write_extended(&mut conn, EXT_HANDSHAKE, &Bencode::Dict(m), &[])?;
+
+    // id the peer wants for the ut_metadata messages we send
+    let mut ut_metadata = 0;
+    loop {
+        let data = read_message(&mut conn)?;
+        if data.len() < 2 || data[0] != EXTENDED {
+            continue;
+        }
+        match data[1] {
+            EXT_HANDSHAKE => {
+                if let Some(Bencode::Dict(m)) = decode(&data[2..]) {
+                    if let Some(Bencode::Dict(inner_m)) = m.get(&ByteString::from_str("m")) {
+                        match inner_m.get(&ByteString::from_str("ut_metadata")) {
+                            Some(Bencode::Number(u)) if *u > 0 && *u < 256 => ut_metadata = *u as u8,
+                            _ => return Ok(()),
+                        }
+                    }
+                }
+            }
+            UT_METADATA if ut_metadata != 0 => on_request(&mut conn, ut_metadata, &data[2..], &metadata)?,
+            _ => {}
+        }
+    }
+}
+
+/// Answers a piece request with the piece, or rejects it if it is out of
+/// range.
+fn on_request(conn: &mut net::TcpStream, ut_metadata: u8, payload: &[u8], metadata: &[u8]) -> Result<(), WireError> {
+    let (m, _) = split_header(payload).ok_or(WireError::Protocol("invalid ut_metadata message"))?;
+    if m.get(&ByteString::from_str("msg_type")) != Some(&Bencode::Number(MSG_REQUEST)) {
+        return Ok(());
+    }
+    let piece = match m.get(&ByteString::from_str("piece")) {
+        Some(Bencode::Number(i)) => *i,
+        _ => return Err(WireError::Protocol("invalid ut_metadata request")),
+    };
+    let pieces = metadata.len().div_ceil(PER_BLOCK as usize) as i64;
+    let mut r = DictMap::new();
+    r.insert(ByteString::from_str("piece"), Bencode::Number(piece));
+    if piece < 0 || piece >= pieces {
+        r.insert(ByteString::from_str("msg_type"), Bencode::Number(MSG_REJECT));
+        return write_extended(conn, ut_metadata, &Bencode::Dict(r), &[]);
+    }
+    r.insert(ByteString::from_str("msg_type"), Bencode::Number(MSG_DATA));
+    r.insert(ByteString::from_str("total_size"), Bencode::Number(metadata.len() as i64));
+    let start = piece as usize * PER_BLOCK as usize;
+    let data = &metadata[start..start + piece_len(metadata.len() as i32, piece as usize)];
+    write_extended(conn, ut_metadata, &Bencode::Dict(r), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::connect;
+
+    #[test]
+    fn serve_metadata() {
+        let mut info = b"d4:name1:a6:pieces".to_vec();
+        info.extend_from_slice(format!("{}:", 40000).as_bytes());
+        info.extend_from_slice(&[b'x'; 40000]);
+        info.push(b'e');
+        let hash = InfoHash::from(sha1::Sha1::from(&info).digest().bytes());
+
+        let seeder = bind("127.0.0.1:0").unwrap();
+        let addr = seeder.local_addr().unwrap().to_string();
+        let held = info.clone();
+        seeder.start(move |h| if *h == hash { Some(held.clone()) } else { None });
+
+        assert_eq!(connect(hash, addr.clone(), 1).unwrap().fetch().unwrap(), info);
+        // unknown torrents are hung up on after the handshake
+        assert!(matches!(connect(InfoHash::default(), addr, 1).unwrap().fetch(), Err(WireError::Io(_))));
+    }
+}